@@ -2,30 +2,58 @@ use std::{env, fs, path::Path};
 
 use dbus_codegen::{ConnectionType, GenOpts};
 
-const XML_FILE: &str = "gnome-session/gnome-session/org.gnome.SessionManager.Presence.xml";
+/// DBUS interfaces to generate code for, along with the prefix to strip from
+/// the names of the generated types and the file to generate.
+const INTERFACES: &[(&str, &str, &str)] = &[
+    (
+        "gnome-session/gnome-session/org.gnome.SessionManager.Presence.xml",
+        "org.gnome",
+        "presence.rs",
+    ),
+    (
+        "xml/org.gnome.ScreenSaver.xml",
+        "org.gnome",
+        "gnome_screensaver.rs",
+    ),
+    (
+        "xml/org.freedesktop.ScreenSaver.xml",
+        "org.freedesktop",
+        "freedesktop_screensaver.rs",
+    ),
+    (
+        "xml/org.freedesktop.login1.xml",
+        "org.freedesktop",
+        "logind.rs",
+    ),
+];
 
 fn main() {
-    let xml = fs::read_to_string(XML_FILE)
-        .unwrap_or_else(|e| panic!("Failed to read DBUS interface metadata at {XML_FILE:?}: {e}"));
-    let options = GenOpts {
-        // Make the names of generated types a bit shorter.
-        skipprefix: Some("org.gnome".into()),
+    let out_dir = env::var_os("OUT_DIR").unwrap();
 
-        // This gives us only client implementations.
-        methodtype: None,
+    for (xml_file, prefix, out_file) in INTERFACES {
+        let xml = fs::read_to_string(xml_file).unwrap_or_else(|e| {
+            panic!("Failed to read DBUS interface metadata at {xml_file:?}: {e}")
+        });
+        let options = GenOpts {
+            // Make the names of generated types a bit shorter.
+            skipprefix: Some((*prefix).into()),
 
-        connectiontype: ConnectionType::Blocking,
+            // This gives us only client implementations.
+            methodtype: None,
 
-        ..Default::default()
-    };
-    let code = dbus_codegen::generate(&xml, &options)
-        .unwrap_or_else(|e| panic!("Failed to generate code for DBUS interface: {e}"));
+            connectiontype: ConnectionType::Blocking,
 
-    let out_dir = env::var_os("OUT_DIR").unwrap();
-    let out_file = Path::new(&out_dir).join("generated.rs");
-    fs::write(&out_file, code)
-        .unwrap_or_else(|e| panic!("Failed to write generated code to file {out_file:?}: {e}"));
+            ..Default::default()
+        };
+        let code = dbus_codegen::generate(&xml, &options)
+            .unwrap_or_else(|e| panic!("Failed to generate code for DBUS interface: {e}"));
+
+        let out_file = Path::new(&out_dir).join(out_file);
+        fs::write(&out_file, code)
+            .unwrap_or_else(|e| panic!("Failed to write generated code to file {out_file:?}: {e}"));
+
+        println!("cargo:rerun-if-changed={xml_file}");
+    }
 
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed={XML_FILE}");
 }
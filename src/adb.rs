@@ -1,120 +1,746 @@
 use std::{
-    ffi::OsStr,
-    io::ErrorKind,
-    net::SocketAddr,
-    os::unix::process::ExitStatusExt,
-    process::{Command, ExitStatus, Stdio},
-    thread,
+    io::{self, ErrorKind, Write},
+    net::{SocketAddr, TcpStream},
     time::{Duration, Instant},
 };
 
 use color_eyre::{
-    eyre::{self, Context, ContextCompat},
-    Result, Section,
+    eyre::{self, Context},
+    Report, Result, Section,
 };
-use nix::sys::signal::Signal;
 
-pub fn shell(
+use auth::Key;
+use message::{Command, Message};
+pub use shell::ShellOutput;
+
+mod auth;
+mod message;
+mod shell;
+
+/// Protocol version we announce. This is the version that allows skipping
+/// the payload checksum.
+const VERSION: u32 = 0x0100_0001;
+
+/// Largest payload we tell the TV it may send us.
+const MAX_DATA: u32 = 256 * 1024;
+
+/// The banner we send in our CNXN message, listing the features we support.
+const HOST_BANNER: &[u8] = b"host::features=shell_v2,cmd\0";
+
+/// AUTH message types.
+const AUTH_TOKEN: u32 = 1;
+const AUTH_SIGNATURE: u32 = 2;
+const AUTH_RSAPUBLICKEY: u32 = 3;
+
+/// How long to wait for the user to accept our key on the TV.
+const PAIR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A connection to the adb daemon on the TV.
+///
+/// This speaks the ADB wire protocol directly over TCP, so no adb server or
+/// binary is needed on this machine.
+pub struct Connection {
+    stream: TcpStream,
     addr: SocketAddr,
-    command: impl IntoIterator<Item = impl AsRef<OsStr>>,
-    timeout: Option<Duration>,
-) -> Result<()> {
-    let start = Instant::now();
-    ensure_connected(addr, timeout)?;
-
-    // Then send a keyevent to the TV to turn it off. 26 corresponds to the
-    // power button.
-    let mut child = Command::new("adb")
-        .args(["-s", &addr.to_string(), "shell"])
-        .args(command)
-        .spawn()
-        .context("Failed to invoke adb")
-        .suggestion("Make sure that adb is installed")?;
-
-    let timeout = timeout.unwrap_or(Duration::MAX);
-    while start.elapsed() < timeout {
-        match child.try_wait().context("Failed to wait for child")? {
-            Some(status) => {
-                return check_status(status, "adb").context("adb shell command failed");
+    next_id: u32,
+    features: Vec<String>,
+}
+
+impl Connection {
+    /// Connects to the TV and performs the CNXN handshake, authenticating
+    /// with `key` if the TV asks for it.
+    ///
+    /// This fails if the TV doesn't already trust `key`. Use [`Self::pair`]
+    /// to ask it to.
+    pub fn connect(addr: SocketAddr, key: &Key, deadline: Option<Instant>) -> Result<Self> {
+        Self::connect_inner(addr, key, deadline, false)
+    }
+
+    /// Like [`Self::connect`], but if the TV doesn't trust `key`, sends it
+    /// our public key and waits for the user to accept it on the TV.
+    pub fn pair(addr: SocketAddr, key: &Key, deadline: Option<Instant>) -> Result<Self> {
+        Self::connect_inner(addr, key, deadline, true)
+    }
+
+    fn connect_inner(
+        addr: SocketAddr,
+        key: &Key,
+        deadline: Option<Instant>,
+        pair: bool,
+    ) -> Result<Self> {
+        log::debug!("Connecting to {addr} over adb");
+
+        let stream = match deadline {
+            Some(deadline) => TcpStream::connect_timeout(&addr, remaining(deadline)?),
+            None => TcpStream::connect(addr),
+        }
+        .with_context(|| format!("Failed to connect to {addr}"))
+        .suggestion("Make sure that the TV is on and that network debugging is enabled")?;
+        stream
+            .set_nodelay(true)
+            .context("Failed to set TCP_NODELAY")?;
+
+        let mut conn = Self {
+            stream,
+            addr,
+            next_id: 1,
+            features: Vec::new(),
+        };
+
+        conn.send(Command::Cnxn, VERSION, MAX_DATA, HOST_BANNER)?;
+
+        let mut sent_signature = false;
+        let mut sent_public_key = false;
+        loop {
+            let msg = match conn.recv(deadline) {
+                Ok(msg) => msg,
+                // adbd drops the connection if the user rejects our key.
+                Err(e)
+                    if sent_public_key && io_error_kind(&e) == Some(ErrorKind::UnexpectedEof) =>
+                {
+                    return Err(e.wrap_err("The TV rejected our key"))
+                }
+                Err(e) if sent_public_key && is_timeout(&e) => {
+                    return Err(e.wrap_err("Nobody accepted our key on the TV in time"))
+                }
+                Err(e) => return Err(e),
+            };
+
+            match msg.command {
+                Command::Cnxn => {
+                    let banner = String::from_utf8_lossy(&msg.payload);
+                    let banner = banner.trim_end_matches('\0');
+                    log::debug!("Connected to {addr} over adb: {banner}");
+                    conn.features = parse_features(banner);
+                    return Ok(conn);
+                }
+                Command::Auth if msg.arg0 != AUTH_TOKEN => {
+                    log::debug!("Ignoring AUTH message of type {}", msg.arg0)
+                }
+                Command::Auth if sent_public_key => eyre::bail!("The TV rejected our key"),
+                Command::Auth if !sent_signature => {
+                    log::debug!("Signing adb auth token");
+                    let signature = key.sign(&msg.payload)?;
+                    conn.send(Command::Auth, AUTH_SIGNATURE, 0, signature)?;
+                    sent_signature = true;
+                }
+                Command::Auth if pair => {
+                    log::info!("Please accept the USB debugging prompt on the TV");
+                    let public_key = format!("{}\0", key.public_key());
+                    conn.send(Command::Auth, AUTH_RSAPUBLICKEY, 0, public_key)?;
+                    sent_public_key = true;
+                }
+                Command::Auth => {
+                    return Err(eyre::eyre!("The TV doesn't trust this computer"))
+                        .suggestion("Run `tv-power pair` to pair with the TV")
+                }
+                command => log::debug!("Ignoring unexpected {command} message during handshake"),
+            }
+        }
+    }
+
+    /// Opens a stream to `service` on the TV, e.g. `shell:ls`.
+    ///
+    /// Once this has succeeded, the service is running on the TV.
+    pub fn open(&mut self, service: &str, deadline: Option<Instant>) -> Result<Stream> {
+        let local_id = self.next_id;
+        self.next_id += 1;
+
+        log::debug!("Opening {service:?} on {}", self.addr);
+        self.send(Command::Open, local_id, 0, format!("{service}\0"))?;
+
+        loop {
+            let msg = self.recv(deadline)?;
+            if msg.arg1 != local_id {
+                log::debug!("Ignoring {} message for stream {}", msg.command, msg.arg1);
+                continue;
+            }
+
+            match msg.command {
+                Command::Okay => {
+                    return Ok(Stream {
+                        local_id,
+                        remote_id: msg.arg0,
+                    })
+                }
+                Command::Clse => eyre::bail!("The TV refused to open {service:?}"),
+                command => log::debug!("Ignoring unexpected {command} message"),
+            }
+        }
+    }
+
+    /// Whether the TV supports `feature`, e.g. `shell_v2`.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Copies everything the TV sends on `stream` to `out` until the TV
+    /// closes it.
+    ///
+    /// If this fails, e.g. by timing out, the stream is closed, which makes
+    /// adbd kill whatever is running on the other end.
+    pub fn copy_stream(
+        &mut self,
+        stream: Stream,
+        out: &mut impl Write,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let res = self.copy_stream_inner(stream, out, deadline);
+        if res.is_err() {
+            log::debug!("Closing stream {} after error", stream.local_id);
+            self.send(Command::Clse, stream.local_id, stream.remote_id, Vec::new())
+                .ok();
+        }
+        res
+    }
+
+    fn copy_stream_inner(
+        &mut self,
+        stream: Stream,
+        out: &mut impl Write,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        loop {
+            let msg = self.recv(deadline)?;
+            if msg.arg1 != stream.local_id {
+                log::debug!("Ignoring {} message for stream {}", msg.command, msg.arg1);
+                continue;
             }
-            None => thread::sleep(Duration::from_millis(50)),
+
+            match msg.command {
+                Command::Wrte => {
+                    out.write_all(&msg.payload)
+                        .context("Failed to write stream output")?;
+                    self.send(Command::Okay, stream.local_id, stream.remote_id, Vec::new())?;
+                }
+                Command::Clse => {
+                    out.flush().context("Failed to write stream output")?;
+                    self.send(Command::Clse, stream.local_id, stream.remote_id, Vec::new())?;
+                    return Ok(());
+                }
+                command => log::debug!("Ignoring unexpected {command} message"),
+            }
+        }
+    }
+
+    /// Checks whether the TV has closed the connection on us.
+    ///
+    /// This can't notice a TV that has silently disappeared from the network,
+    /// but catches the common case where adbd has restarted.
+    fn is_alive(&self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
         }
+        let alive = match self.stream.peek(&mut [0]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == ErrorKind::WouldBlock,
+        };
+        alive && self.stream.set_nonblocking(false).is_ok()
     }
 
-    let status = child
-        .wait()
-        .context("Failed to wait for child after killing it")?;
-    check_status(status, "adb").context("adb shell command out")
+    fn send(
+        &mut self,
+        command: Command,
+        arg0: u32,
+        arg1: u32,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        Message::new(command, arg0, arg1, payload).write_to(&mut self.stream)
+    }
+
+    fn recv(&mut self, deadline: Option<Instant>) -> Result<Message> {
+        let timeout = deadline.map(remaining).transpose()?;
+        self.stream
+            .set_read_timeout(timeout)
+            .context("Failed to set socket timeout")?;
+
+        Message::read_from(&mut self.stream).map_err(|e| {
+            if is_timeout(&e) {
+                e.wrap_err(format!("Timed out waiting for {}", self.addr))
+            } else {
+                e
+            }
+        })
+    }
 }
 
-pub fn send_keycode(addr: SocketAddr, keycode: i32, timeout: Option<Duration>) -> Result<()> {
-    shell(addr, ["input", "keyevent", &keycode.to_string()], timeout)
+/// An open stream to a service on the TV.
+#[derive(Debug, Clone, Copy)]
+pub struct Stream {
+    local_id: u32,
+    remote_id: u32,
 }
 
-pub fn send_keycodes(
+/// A lazily (re)connected adb connection to the TV.
+///
+/// The connection is kept open between commands, and is reestablished when
+/// the TV drops it, e.g. when turning the TV off kills its WiFi.
+pub struct Session {
     addr: SocketAddr,
-    keycodes: impl IntoIterator<Item = i32>,
-    timeout: Option<Duration>,
-) -> Result<()> {
-    let cmd = keycodes
-        .into_iter()
-        .map(|n| format!("input keyevent {n}"))
-        .collect::<Vec<_>>()
-        .join(" && ");
-
-    shell(addr, [cmd], timeout)
+    key: Key,
+    conn: Option<Connection>,
 }
 
-/// Makes sure we're connected to the TV.
-fn ensure_connected(addr: SocketAddr, timeout: Option<Duration>) -> Result<()> {
-    let start = Instant::now();
-    let mut child = Command::new("adb")
-        .arg("connect")
-        .arg(addr.to_string())
-        .stdout(Stdio::null())
-        .spawn()
-        .context("Failed to invoke adb")
-        .suggestion("Make sure that adb is installed")?;
-
-    let timeout = timeout.unwrap_or(Duration::MAX);
-    while start.elapsed() < timeout {
-        match child.try_wait().context("Failed to wait for child")? {
-            Some(status) => {
-                return check_status(status, "adb")
-                    .with_context(|| format!("Connecting to {addr} over adb failed"))
+impl Session {
+    pub fn new(addr: SocketAddr) -> Result<Self> {
+        Ok(Self {
+            addr,
+            key: Key::load_or_generate()?,
+            conn: None,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Runs a shell command on the TV, copying its output to ours.
+    pub fn shell(&mut self, command: &str, timeout: Option<Duration>) -> Result<()> {
+        let output = self.capture(command, timeout)?;
+
+        io::stdout()
+            .write_all(output.stdout.as_bytes())
+            .context("Failed to write shell output")?;
+        io::stderr()
+            .write_all(output.stderr.as_bytes())
+            .context("Failed to write shell output")?;
+
+        output.check().context("adb shell command failed")
+    }
+
+    /// Runs a shell command on the TV and returns its output.
+    ///
+    /// The command is killed if it doesn't finish within `timeout`.
+    pub fn capture(&mut self, command: &str, timeout: Option<Duration>) -> Result<ShellOutput> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        // Connect first so that we know which shell protocol we can use.
+        let shell_v2 = self.with_connection(deadline, |conn| Ok(conn.has_feature("shell_v2")))?;
+        let service = if shell_v2 {
+            format!("shell,v2,raw:{command}")
+        } else {
+            format!("shell:{command}")
+        };
+
+        let stream = self.open(&service, deadline)?;
+        let mut data = Vec::new();
+        self.with_connection(deadline, |conn| {
+            conn.copy_stream(stream, &mut data, deadline)
+        })
+        .context("adb shell command failed")?;
+
+        if shell_v2 {
+            ShellOutput::from_v2(&data).context("Failed to parse shell output")
+        } else {
+            Ok(ShellOutput::from_v1(&data))
+        }
+    }
+
+    /// Runs a command on the TV without a shell, copying its output to `out`
+    /// byte for byte.
+    ///
+    /// Unlike [`Session::shell`], this doesn't mangle binary output, but it
+    /// mixes stdout and stderr and can't tell whether the command failed.
+    pub fn exec(
+        &mut self,
+        command: &str,
+        out: &mut impl Write,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let stream = self.open(&format!("exec:{command}"), deadline)?;
+        self.with_connection(deadline, |conn| conn.copy_stream(stream, out, deadline))
+            .context("adb exec command failed")
+    }
+
+    pub fn send_keycode(&mut self, keycode: i32, timeout: Option<Duration>) -> Result<()> {
+        self.shell(&format!("input keyevent {keycode}"), timeout)
+    }
+
+    fn open(&mut self, service: &str, deadline: Option<Instant>) -> Result<Stream> {
+        let reused = self.conn.is_some();
+        match self.with_connection(deadline, |conn| conn.open(service, deadline)) {
+            // A reused connection may have died since we last used it. Nothing
+            // has run on the TV if it hung up before accepting the stream, so
+            // it's safe to try again with a fresh connection.
+            Err(e) if reused && is_disconnect(&e) => {
+                log::debug!("adb connection to {} was stale: {e:#}", self.addr);
+                self.with_connection(deadline, |conn| conn.open(service, deadline))
             }
-            None => thread::sleep(Duration::from_millis(50)),
+            res => res,
         }
     }
 
-    log::error!("Connecting to TV timed out");
+    /// Runs `f` on our connection, connecting first if necessary. The
+    /// connection is dropped if `f` fails, as it may be in any state then.
+    fn with_connection<T>(
+        &mut self,
+        deadline: Option<Instant>,
+        f: impl FnOnce(&mut Connection) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(conn) = &self.conn {
+            if !conn.is_alive() {
+                log::info!("adb connection to {} was closed by the TV", self.addr);
+                self.conn = None;
+            }
+        }
+
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::connect(self.addr, &self.key, deadline)?;
+                log::info!("Connected to {} over adb", self.addr);
+                self.conn.insert(conn)
+            }
+        };
+
+        let res = f(conn);
+        if let Err(e) = &res {
+            self.conn = None;
+            log::info!("Dropped adb connection to {}: {e}", self.addr);
+        }
+        res
+    }
+}
 
-    match child.kill() {
-        // Child died before we could kill it.
-        Err(e) if e.kind() != ErrorKind::InvalidInput => {
-            return Err(e).context("Failed to kill child")
+#[cfg(test)]
+impl Session {
+    /// A session that authenticates with the key that the tests use, rather
+    /// than the user's.
+    pub fn with_test_key(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            key: auth::tests::key(),
+            conn: None,
         }
-        _ => {}
     }
+}
+
+/// Makes the TV trust our key, asking the user to accept it on the TV if
+/// necessary.
+pub fn pair(addr: SocketAddr) -> Result<()> {
+    let key = Key::load_or_generate()?;
+    let deadline = Instant::now() + PAIR_TIMEOUT;
+    Connection::pair(addr, &key, Some(deadline)).context("Failed to pair with the TV")?;
+    log::info!("Paired with the TV");
+    Ok(())
+}
+
+/// Quotes `s` so that the TV's shell passes it on as a single argument.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Parses the features out of the banner in the TV's CNXN message, which looks
+/// like `device::ro.product.name=foo;ro.product.model=bar;features=shell_v2,cmd`.
+fn parse_features(banner: &str) -> Vec<String> {
+    let props = banner.split_once("::").map_or("", |(_, props)| props);
+    props
+        .split(';')
+        .find_map(|prop| prop.strip_prefix("features="))
+        .map(|features| features.split(',').map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Returns the time left until `deadline`, or an error if it has passed.
+fn remaining(deadline: Instant) -> Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(d) if !d.is_zero() => Ok(d),
+        _ => eyre::bail!("Timed out talking to the TV over adb"),
+    }
+}
+
+fn io_error_kind(e: &Report) -> Option<ErrorKind> {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .map(io::Error::kind)
+}
 
-    let status = child
-        .wait()
-        .context("Failed to wait for child after killing it")?;
-    check_status(status, "adb").context("Connecting to TV timed out")
+fn is_disconnect(e: &Report) -> bool {
+    matches!(
+        io_error_kind(e),
+        Some(
+            ErrorKind::UnexpectedEof
+                | ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+        )
+    )
 }
 
-fn check_status(status: ExitStatus, name: &str) -> Result<()> {
-    match status
-        .code()
-        .with_context(|| format!("{name} didn't return a status code?"))?
-    {
-        0 => Ok(()),
-        n => match status.signal() {
-            Some(sig) => match Signal::try_from(sig) {
-                Ok(sig) => eyre::bail!("{name} died from signal {sig}"),
-                Err(_) => eyre::bail!("{name} died from unknown signal {sig}"),
-            },
-            None => eyre::bail!("{name} exited with status {n}"),
-        },
+fn is_timeout(e: &Report) -> bool {
+    matches!(
+        io_error_kind(e),
+        Some(ErrorKind::WouldBlock | ErrorKind::TimedOut)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use shell::tests::packet;
+
+    const DEVICE_BANNER: &[u8] = b"device::ro.product.name=tv;features=shell_v2,cmd\0";
+    const TOKEN: [u8; 20] = [0x42; 20];
+
+    /// A fake adbd listening on loopback, which runs `script` against the
+    /// first connection it gets.
+    struct FakeAdbd {
+        addr: SocketAddr,
+        thread: JoinHandle<()>,
+    }
+
+    impl FakeAdbd {
+        fn spawn(script: impl FnOnce(&mut TcpStream) + Send + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let thread = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                script(&mut stream);
+            });
+            Self { addr, thread }
+        }
+
+        /// Waits for the script to finish, failing the test if it panicked.
+        fn join(self) {
+            self.thread.join().expect("fake adbd failed");
+        }
+    }
+
+    fn deadline() -> Option<Instant> {
+        Some(Instant::now() + Duration::from_secs(5))
+    }
+
+    fn expect(stream: &mut TcpStream, command: Command) -> Message {
+        let msg = Message::read_from(stream).unwrap();
+        assert_eq!(msg.command, command);
+        msg
+    }
+
+    fn send(stream: &mut TcpStream, command: Command, arg0: u32, arg1: u32, payload: &[u8]) {
+        Message::new(command, arg0, arg1, payload)
+            .write_to(stream)
+            .unwrap();
+    }
+
+    /// Expects the host's CNXN message.
+    fn expect_cnxn(stream: &mut TcpStream) {
+        let msg = expect(stream, Command::Cnxn);
+        assert_eq!((msg.arg0, msg.arg1), (VERSION, MAX_DATA));
+        assert_eq!(msg.payload, HOST_BANNER);
+    }
+
+    /// Expects a signature of our token, and checks that it's valid.
+    fn expect_signature(stream: &mut TcpStream) {
+        let msg = expect(stream, Command::Auth);
+        assert_eq!(msg.arg0, AUTH_SIGNATURE);
+        assert!(auth::tests::verify(
+            &auth::tests::key(),
+            &TOKEN,
+            &msg.payload
+        ));
+    }
+
+    /// Performs the handshake without asking for authentication.
+    fn handshake(stream: &mut TcpStream, banner: &[u8]) {
+        expect_cnxn(stream);
+        send(stream, Command::Cnxn, VERSION, MAX_DATA, banner);
+    }
+
+    /// Accepts a stream to `service`, sends `chunks` on it and closes it.
+    fn serve(stream: &mut TcpStream, service: &str, chunks: &[&[u8]]) {
+        const REMOTE_ID: u32 = 7;
+
+        let msg = expect(stream, Command::Open);
+        assert_eq!(msg.payload, format!("{service}\0").as_bytes());
+        let local_id = msg.arg0;
+        send(stream, Command::Okay, REMOTE_ID, local_id, &[]);
+
+        for chunk in chunks {
+            send(stream, Command::Wrte, REMOTE_ID, local_id, chunk);
+            let msg = expect(stream, Command::Okay);
+            assert_eq!((msg.arg0, msg.arg1), (local_id, REMOTE_ID));
+        }
+
+        send(stream, Command::Clse, REMOTE_ID, local_id, &[]);
+        let msg = expect(stream, Command::Clse);
+        assert_eq!((msg.arg0, msg.arg1), (local_id, REMOTE_ID));
+    }
+
+    #[test]
+    fn connect() {
+        let adbd = FakeAdbd::spawn(|stream| handshake(stream, DEVICE_BANNER));
+        let conn = Connection::connect(adbd.addr, &auth::tests::key(), deadline()).unwrap();
+        assert!(conn.has_feature("shell_v2"));
+        assert!(conn.has_feature("cmd"));
+        assert!(!conn.has_feature("ro.product.name=tv"));
+        adbd.join();
+    }
+
+    #[test]
+    fn auth_signature() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            expect_cnxn(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+            expect_signature(stream);
+            send(stream, Command::Cnxn, VERSION, MAX_DATA, DEVICE_BANNER);
+        });
+        Connection::connect(adbd.addr, &auth::tests::key(), deadline()).unwrap();
+        adbd.join();
+    }
+
+    #[test]
+    fn auth_untrusted() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            expect_cnxn(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+            expect_signature(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+        });
+        let err = Connection::connect(adbd.addr, &auth::tests::key(), deadline())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "The TV doesn't trust this computer");
+        adbd.join();
+    }
+
+    #[test]
+    fn auth_public_key() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            expect_cnxn(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+            expect_signature(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+
+            let msg = expect(stream, Command::Auth);
+            assert_eq!(msg.arg0, AUTH_RSAPUBLICKEY);
+            let public_key = format!("{}\0", auth::tests::key().public_key());
+            assert_eq!(msg.payload, public_key.as_bytes());
+
+            // The user accepted the key on the TV.
+            send(stream, Command::Cnxn, VERSION, MAX_DATA, DEVICE_BANNER);
+        });
+        Connection::pair(adbd.addr, &auth::tests::key(), deadline()).unwrap();
+        adbd.join();
+    }
+
+    #[test]
+    fn auth_public_key_rejected() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            expect_cnxn(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+            expect_signature(stream);
+            send(stream, Command::Auth, AUTH_TOKEN, 0, &TOKEN);
+            expect(stream, Command::Auth);
+            // adbd hangs up if the user rejects the key.
+        });
+        let err = Connection::pair(adbd.addr, &auth::tests::key(), deadline())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "The TV rejected our key");
+        adbd.join();
+    }
+
+    #[test]
+    fn shell_v2() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            handshake(stream, DEVICE_BANNER);
+            let output = [packet(1, b"hello\n"), packet(2, b"oops\n"), packet(3, &[3])];
+            let chunks: Vec<&[u8]> = output.iter().map(Vec::as_slice).collect();
+            serve(stream, "shell,v2,raw:echo hello", &chunks);
+        });
+
+        let output = Session::with_test_key(adbd.addr)
+            .capture("echo hello", Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(
+            output,
+            ShellOutput {
+                stdout: "hello\n".to_owned(),
+                stderr: "oops\n".to_owned(),
+                exit_code: Some(3),
+            }
+        );
+        assert_eq!(
+            output.check().unwrap_err().to_string(),
+            "Command exited with status 3: oops"
+        );
+        adbd.join();
+    }
+
+    #[test]
+    fn shell_v1() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            handshake(stream, b"device::ro.product.name=tv\0");
+            serve(stream, "shell:echo hello", &[b"hello\n"]);
+        });
+
+        let output = Session::with_test_key(adbd.addr)
+            .capture("echo hello", Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(output.stdout, "hello\n");
+        assert_eq!(output.exit_code, None);
+        adbd.join();
+    }
+
+    #[test]
+    fn exec_binary() {
+        // Not valid UTF-8, and contains bytes that a shell's pty would mangle.
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\xff\xfe";
+
+        let adbd = FakeAdbd::spawn(|stream| {
+            handshake(stream, DEVICE_BANNER);
+            serve(stream, "exec:screencap -p", &[&PNG[..5], &PNG[5..]]);
+        });
+
+        let mut out = Vec::new();
+        Session::with_test_key(adbd.addr)
+            .exec("screencap -p", &mut out, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(out, PNG);
+        adbd.join();
+    }
+
+    #[test]
+    fn open_refused() {
+        let adbd = FakeAdbd::spawn(|stream| {
+            handshake(stream, DEVICE_BANNER);
+            let msg = expect(stream, Command::Open);
+            send(stream, Command::Clse, 0, msg.arg0, &[]);
+        });
+
+        let mut conn = Connection::connect(adbd.addr, &auth::tests::key(), deadline()).unwrap();
+        let err = conn.open("reboot:", deadline()).unwrap_err();
+        assert_eq!(err.to_string(), "The TV refused to open \"reboot:\"");
+        adbd.join();
+    }
+
+    #[test]
+    fn features() {
+        assert_eq!(
+            parse_features("device::ro.product.name=tv;features=shell_v2,cmd;ro.x=y"),
+            ["shell_v2", "cmd"]
+        );
+        assert!(parse_features("device::ro.product.name=tv").is_empty());
+        assert!(parse_features("device").is_empty());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
     }
 }
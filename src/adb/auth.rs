@@ -0,0 +1,223 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, LineEnding},
+    pkcs8::DecodePrivateKey,
+    BigUint, Pkcs1v15Sign, PublicKeyParts, RsaPrivateKey,
+};
+use sha1::Sha1;
+
+use crate::config;
+
+/// Name of the private key file in the config directory. This matches what
+/// adb calls it in `~/.android`.
+const KEY_FILE: &str = "adbkey";
+
+/// Size of the RSA keys that adbd accepts.
+const KEY_BITS: usize = 2048;
+
+/// The RSA key we authenticate to the TV with.
+pub struct Key {
+    private: RsaPrivateKey,
+    public: String,
+}
+
+impl Key {
+    /// Loads our key from the config directory, generating and saving a new
+    /// one if it doesn't exist yet.
+    pub fn load_or_generate() -> Result<Self> {
+        let file = key_file();
+
+        let private = match fs::read_to_string(&file) {
+            Ok(pem) => {
+                log::debug!("Using adb key {file:?}");
+                RsaPrivateKey::from_pkcs1_pem(&pem)
+                    .or_else(|_| RsaPrivateKey::from_pkcs8_pem(&pem))
+                    .with_context(|| format!("Failed to parse adb key {file:?}"))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => generate(&file)?,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read adb key {file:?}")),
+        };
+
+        Self::new(private)
+    }
+
+    fn new(private: RsaPrivateKey) -> Result<Self> {
+        let public = encode_public_key(&private)?;
+        Ok(Self { private, public })
+    }
+
+    /// Signs an AUTH token sent by the TV.
+    pub fn sign(&self, token: &[u8]) -> Result<Vec<u8>> {
+        // adbd sends a 20 byte token which it treats as a SHA-1 digest, so
+        // it's signed as-is rather than being hashed first.
+        self.private
+            .sign(Pkcs1v15Sign::new::<Sha1>(), token)
+            .context("Failed to sign adb auth token")
+    }
+
+    /// The public key in the format that adbd expects, including the
+    /// `user@host` comment.
+    pub fn public_key(&self) -> &str {
+        &self.public
+    }
+}
+
+fn key_file() -> PathBuf {
+    config::config_dir().join(KEY_FILE)
+}
+
+fn generate(file: &Path) -> Result<RsaPrivateKey> {
+    log::info!("Generating a new adb key in {file:?}");
+
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), KEY_BITS)
+        .context("Failed to generate adb key")?;
+    let pem = key
+        .to_pkcs1_pem(LineEnding::LF)
+        .context("Failed to encode adb key")?;
+
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create directory {dir:?}"))?;
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(file)
+        .and_then(|mut f| f.write_all(pem.as_bytes()))
+        .with_context(|| format!("Failed to write adb key to {file:?}"))?;
+
+    let public = encode_public_key(&key)?;
+    let pub_file = file.with_extension("pub");
+    fs::write(&pub_file, format!("{public}\n"))
+        .with_context(|| format!("Failed to write adb public key to {pub_file:?}"))?;
+
+    Ok(key)
+}
+
+/// Encodes the public half of `key` the way adb does.
+///
+/// This is the base64 encoding of Android's `RSAPublicKey` struct, which holds
+/// the modulus along with some precomputed Montgomery values, followed by a
+/// `user@host` comment.
+fn encode_public_key(key: &RsaPrivateKey) -> Result<String> {
+    let words = KEY_BITS / 32;
+    let n = key.n();
+    eyre::ensure!(n.bits() == KEY_BITS, "adb key must be {KEY_BITS} bits");
+
+    // -1 / n[0] mod 2^32. The inverse is found with Newton's method, which
+    // doubles the number of correct bits for each iteration.
+    let n_bytes = to_le_bytes_padded(n, KEY_BITS / 8);
+    let n0 = u32::from_le_bytes(n_bytes[..4].try_into().unwrap());
+    let mut inv = n0;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+    }
+    let n0inv = inv.wrapping_neg();
+
+    // R^2 mod n, where R = 2^KEY_BITS.
+    let rr = (BigUint::from(1u32) << (2 * KEY_BITS)) % n;
+
+    eyre::ensure!(
+        key.e().bits() <= 32,
+        "adb key has an unsupported public exponent"
+    );
+
+    let mut buf = Vec::with_capacity(3 * 4 + 2 * KEY_BITS / 8);
+    buf.extend_from_slice(&(words as u32).to_le_bytes());
+    buf.extend_from_slice(&n0inv.to_le_bytes());
+    buf.extend_from_slice(&n_bytes);
+    buf.extend_from_slice(&to_le_bytes_padded(&rr, KEY_BITS / 8));
+    buf.extend_from_slice(&to_le_bytes_padded(key.e(), 4));
+
+    Ok(format!("{} {}", BASE64.encode(buf), user_at_host()))
+}
+
+fn to_le_bytes_padded(n: &BigUint, len: usize) -> Vec<u8> {
+    let mut bytes = n.to_bytes_le();
+    bytes.resize(len, 0);
+    bytes
+}
+
+fn user_at_host() -> String {
+    let user = env::var("USER").unwrap_or_else(|_| "unknown".to_owned());
+    let host = nix::unistd::gethostname()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_owned());
+    format!("{user}@{host}")
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use rsa::{PublicKey, RsaPublicKey};
+
+    use super::*;
+
+    /// A key that's only used by the tests, so that they don't have to
+    /// generate one, which is slow in debug builds.
+    const TEST_KEY: &str = include_str!("testdata/adbkey");
+
+    pub fn key() -> Key {
+        Key::new(RsaPrivateKey::from_pkcs1_pem(TEST_KEY).unwrap()).unwrap()
+    }
+
+    /// Checks `signature` the way adbd does.
+    pub fn verify(key: &Key, token: &[u8], signature: &[u8]) -> bool {
+        RsaPublicKey::from(&key.private)
+            .verify(Pkcs1v15Sign::new::<Sha1>(), token, signature)
+            .is_ok()
+    }
+
+    fn word(buf: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(buf[i..i + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn sign() {
+        let key = key();
+        let token = [0x5a; 20];
+        let signature = key.sign(&token).unwrap();
+        assert_eq!(signature.len(), KEY_BITS / 8);
+        assert!(verify(&key, &token, &signature));
+        assert!(!verify(&key, &[0xa5; 20], &signature));
+    }
+
+    #[test]
+    fn public_key_layout() {
+        let key = key();
+        let (encoded, comment) = key.public_key().split_once(' ').unwrap();
+        assert_eq!(comment, user_at_host());
+
+        let buf = BASE64.decode(encoded).unwrap();
+        let len = KEY_BITS / 8;
+        assert_eq!(buf.len(), 4 + 4 + len + len + 4);
+
+        // The modulus length in words, followed by n0inv.
+        assert_eq!(word(&buf, 0), (KEY_BITS / 32) as u32);
+        let n0inv = word(&buf, 4);
+
+        // The modulus, R^2 mod n and the exponent, all little-endian.
+        let n = BigUint::from_bytes_le(&buf[8..8 + len]);
+        let rr = BigUint::from_bytes_le(&buf[8 + len..8 + 2 * len]);
+        let e = word(&buf, 8 + 2 * len);
+        assert_eq!(&n, key.private.n());
+        assert_eq!(rr, (BigUint::from(1u32) << (2 * KEY_BITS)) % &n);
+        assert_eq!(BigUint::from(e), *key.private.e());
+
+        // n0inv is -1 / n[0] mod 2^32, so n[0] * n0inv must be -1.
+        assert_eq!(word(&buf, 8).wrapping_mul(n0inv), u32::MAX);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{Read, Write},
+};
+
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+
+/// The length of a message header on the wire.
+const HEADER_LEN: usize = 24;
+
+/// Largest payload we're willing to accept from the TV. Newer adbd versions
+/// send at most 1 MiB per message.
+const MAX_PAYLOAD: u32 = 1024 * 1024;
+
+/// ADB message commands.
+///
+/// These are the ASCII names of the commands interpreted as little-endian
+/// `u32`s. See
+/// https://android.googlesource.com/platform/packages/modules/adb/+/refs/heads/master/protocol.txt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Command {
+    Cnxn = 0x4e58_4e43,
+    Auth = 0x4854_5541,
+    Open = 0x4e45_504f,
+    Okay = 0x5941_4b4f,
+    Clse = 0x4553_4c43,
+    Wrte = 0x4554_5257,
+}
+
+impl TryFrom<u32> for Command {
+    type Error = eyre::Report;
+
+    fn try_from(value: u32) -> Result<Self> {
+        let command = match value {
+            0x4e58_4e43 => Self::Cnxn,
+            0x4854_5541 => Self::Auth,
+            0x4e45_504f => Self::Open,
+            0x5941_4b4f => Self::Okay,
+            0x4553_4c43 => Self::Clse,
+            0x4554_5257 => Self::Wrte,
+            n => eyre::bail!("Unknown adb command {n:#010x}"),
+        };
+        Ok(command)
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Command::Cnxn => "CNXN",
+            Command::Auth => "AUTH",
+            Command::Open => "OPEN",
+            Command::Okay => "OKAY",
+            Command::Clse => "CLSE",
+            Command::Wrte => "WRTE",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// A single message in the ADB wire protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub command: Command,
+    pub arg0: u32,
+    pub arg1: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(command: Command, arg0: u32, arg1: u32, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            command,
+            arg0,
+            arg1,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        let command = self.command as u32;
+        let len = u32::try_from(self.payload.len()).context("adb payload is too large")?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        for word in [
+            command,
+            self.arg0,
+            self.arg1,
+            len,
+            checksum(&self.payload),
+            !command,
+        ] {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.payload);
+
+        writer
+            .write_all(&buf)
+            .with_context(|| format!("Failed to send {} message", self.command))
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .context("Failed to read adb message header")?;
+
+        let word = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let (command, arg0, arg1, len, sum, magic) =
+            (word(0), word(1), word(2), word(3), word(4), word(5));
+
+        eyre::ensure!(
+            command == !magic,
+            "Corrupt adb message header: magic {magic:#010x} doesn't match command {command:#010x}"
+        );
+        let command = Command::try_from(command)?;
+        eyre::ensure!(
+            len <= MAX_PAYLOAD,
+            "{command} message payload of {len} bytes is too large"
+        );
+
+        let mut payload = vec![0; len as usize];
+        reader
+            .read_exact(&mut payload)
+            .with_context(|| format!("Failed to read {command} message payload"))?;
+
+        // Newer versions of adbd don't bother calculating the checksum and
+        // send zero instead.
+        eyre::ensure!(
+            sum == 0 || sum == checksum(&payload),
+            "Checksum mismatch in {command} message"
+        );
+
+        Ok(Self {
+            command,
+            arg0,
+            arg1,
+            payload,
+        })
+    }
+}
+
+/// Calculates the "CRC32" used by older ADB versions, which is actually just a
+/// sum of all the bytes.
+fn checksum(payload: &[u8]) -> u32 {
+    payload
+        .iter()
+        .fold(0u32, |sum, &b| sum.wrapping_add(u32::from(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(msg: &Message) -> Vec<u8> {
+        let mut buf = Vec::new();
+        msg.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn encode_header() {
+        let buf = encode(&Message::new(Command::Wrte, 3, 7, b"ab".to_vec()));
+        assert_eq!(buf.len(), HEADER_LEN + 2);
+        assert_eq!(&buf[0..4], b"WRTE");
+        assert_eq!(&buf[4..8], &3u32.to_le_bytes());
+        assert_eq!(&buf[8..12], &7u32.to_le_bytes());
+        assert_eq!(&buf[12..16], &2u32.to_le_bytes());
+        assert_eq!(&buf[16..20], &(0x61u32 + 0x62).to_le_bytes());
+        assert_eq!(&buf[20..24], &(!(Command::Wrte as u32)).to_le_bytes());
+        assert_eq!(&buf[24..], b"ab");
+    }
+
+    #[test]
+    fn command_names() {
+        for (command, name) in [
+            (Command::Cnxn, b"CNXN"),
+            (Command::Auth, b"AUTH"),
+            (Command::Open, b"OPEN"),
+            (Command::Okay, b"OKAY"),
+            (Command::Clse, b"CLSE"),
+            (Command::Wrte, b"WRTE"),
+        ] {
+            assert_eq!(&(command as u32).to_le_bytes(), name);
+            assert_eq!(command.to_string().as_bytes(), name);
+            assert_eq!(Command::try_from(command as u32).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0xff; 3]), 0x2fd);
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = Message::new(Command::Open, 1, 0, b"shell:ls\0".to_vec());
+        let buf = encode(&msg);
+        assert_eq!(Message::read_from(&mut buf.as_slice()).unwrap(), msg);
+    }
+
+    #[test]
+    fn zero_checksum() {
+        let mut buf = encode(&Message::new(Command::Okay, 1, 2, b"x".to_vec()));
+        buf[16..20].fill(0);
+        Message::read_from(&mut buf.as_slice()).unwrap();
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut buf = encode(&Message::new(Command::Wrte, 1, 2, b"x".to_vec()));
+        buf[16] ^= 1;
+        let err = Message::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "Checksum mismatch in WRTE message");
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut buf = encode(&Message::new(Command::Cnxn, 1, 2, Vec::new()));
+        buf[20] ^= 1;
+        let err = Message::read_from(&mut buf.as_slice()).unwrap_err();
+        assert!(err.to_string().starts_with("Corrupt adb message header"));
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut buf = encode(&Message::new(Command::Cnxn, 1, 2, Vec::new()));
+        buf[0..4].copy_from_slice(b"SYNC");
+        buf[20..24].copy_from_slice(&(!u32::from_le_bytes(*b"SYNC")).to_le_bytes());
+        let err = Message::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown adb command 0x434e5953");
+    }
+
+    #[test]
+    fn payload_too_large() {
+        let mut buf = encode(&Message::new(Command::Wrte, 1, 2, Vec::new()));
+        buf[12..16].copy_from_slice(&(MAX_PAYLOAD + 1).to_le_bytes());
+        let err = Message::read_from(&mut buf.as_slice()).unwrap_err();
+        assert!(err.to_string().ends_with("is too large"));
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let buf = encode(&Message::new(Command::Wrte, 1, 2, b"abc".to_vec()));
+        let err = Message::read_from(&mut &buf[..buf.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to read WRTE message payload");
+    }
+}
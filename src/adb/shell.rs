@@ -0,0 +1,147 @@
+use color_eyre::{eyre, Result};
+
+/// Packet IDs in the shell v2 protocol.
+const ID_STDOUT: u8 = 1;
+const ID_STDERR: u8 = 2;
+const ID_EXIT: u8 = 3;
+
+/// The output of a shell command run on the TV.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ShellOutput {
+    pub stdout: String,
+    pub stderr: String,
+
+    /// The command's exit code. This is only known if the TV supports the
+    /// shell v2 protocol.
+    pub exit_code: Option<u8>,
+}
+
+impl ShellOutput {
+    /// Returns an error if the command is known to have failed.
+    pub fn check(&self) -> Result<()> {
+        match self.exit_code {
+            None | Some(0) => Ok(()),
+            Some(n) => match self.stderr.trim() {
+                "" => eyre::bail!("Command exited with status {n}"),
+                stderr => eyre::bail!("Command exited with status {n}: {stderr}"),
+            },
+        }
+    }
+
+    /// Parses the output of a command run with the plain shell protocol,
+    /// which mixes stdout and stderr and doesn't tell us the exit code.
+    pub(super) fn from_v1(data: &[u8]) -> Self {
+        Self {
+            stdout: String::from_utf8_lossy(data).into_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Parses the output of a command run with the shell v2 protocol.
+    ///
+    /// This consists of packets with a one byte ID and a little-endian `u32`
+    /// length, followed by that many bytes of data.
+    pub(super) fn from_v2(mut data: &[u8]) -> Result<Self> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut exit_code = None;
+
+        while !data.is_empty() {
+            eyre::ensure!(data.len() >= 5, "Truncated shell packet header");
+            let id = data[0];
+            let len = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+            eyre::ensure!(data.len() >= 5 + len, "Truncated shell packet");
+            let payload = &data[5..5 + len];
+            data = &data[5 + len..];
+
+            match id {
+                ID_STDOUT => stdout.extend_from_slice(payload),
+                ID_STDERR => stderr.extend_from_slice(payload),
+                ID_EXIT => exit_code = payload.first().copied(),
+                id => log::debug!("Ignoring shell packet with ID {id}"),
+            }
+        }
+
+        Ok(Self {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            exit_code,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Encodes a shell v2 packet.
+    pub fn packet(id: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![id];
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn v2() {
+        let data = [
+            packet(ID_STDOUT, b"hello "),
+            packet(ID_STDERR, b"oops"),
+            packet(ID_STDOUT, b"world\n"),
+            packet(ID_EXIT, &[0]),
+        ]
+        .concat();
+        let output = ShellOutput::from_v2(&data).unwrap();
+        assert_eq!(
+            output,
+            ShellOutput {
+                stdout: "hello world\n".to_owned(),
+                stderr: "oops".to_owned(),
+                exit_code: Some(0),
+            }
+        );
+        output.check().unwrap();
+    }
+
+    #[test]
+    fn v2_exit_status() {
+        let data = [packet(ID_STDERR, b"no such file\n"), packet(ID_EXIT, &[2])].concat();
+        let output = ShellOutput::from_v2(&data).unwrap();
+        assert_eq!(output.exit_code, Some(2));
+        assert_eq!(
+            output.check().unwrap_err().to_string(),
+            "Command exited with status 2: no such file"
+        );
+
+        let output = ShellOutput::from_v2(&packet(ID_EXIT, &[255])).unwrap();
+        assert_eq!(
+            output.check().unwrap_err().to_string(),
+            "Command exited with status 255"
+        );
+    }
+
+    #[test]
+    fn v2_ignores_unknown_packets() {
+        let data = [packet(0, b"stdin"), packet(ID_STDOUT, b"ok")].concat();
+        let output = ShellOutput::from_v2(&data).unwrap();
+        assert_eq!(output.stdout, "ok");
+        assert_eq!(output.exit_code, None);
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let data = packet(ID_STDOUT, b"hello");
+        let err = ShellOutput::from_v2(&data[..data.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated shell packet");
+        let err = ShellOutput::from_v2(&data[..3]).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated shell packet header");
+    }
+
+    #[test]
+    fn v1() {
+        let output = ShellOutput::from_v1(b"mixed output\n");
+        assert_eq!(output.stdout, "mixed output\n");
+        assert_eq!(output.exit_code, None);
+        output.check().unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, time::Duration};
+
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+
+use crate::adb;
+
+/// How long to wait for the TV to start an app.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Launcher categories to look for apps in. Android TV apps usually only have
+/// the leanback one, but sideloaded phone apps only have the regular one.
+const LAUNCHER_CATEGORIES: [&str; 2] = [
+    "android.intent.category.LEANBACK_LAUNCHER",
+    "android.intent.category.LAUNCHER",
+];
+
+/// Looks up `app` among the aliases in the config file, returning the package
+/// name it refers to, or `app` itself if it isn't an alias.
+pub fn resolve<'a>(aliases: &'a HashMap<String, String>, app: &'a str) -> &'a str {
+    match aliases.get(&app.to_lowercase()) {
+        Some(package) => {
+            log::debug!("App alias {app} refers to {package}");
+            package
+        }
+        None => app,
+    }
+}
+
+/// The shell command that launches `package`'s main activity.
+pub fn launch_command(package: &str) -> String {
+    let categories = LAUNCHER_CATEGORIES
+        .iter()
+        .map(|c| format!("-c {c}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("monkey -p {} {categories} 1", adb::quote(package))
+}
+
+pub fn launch(adb: &mut adb::Session, package: &str) -> Result<()> {
+    log::info!("Launching {package}");
+    let output = adb.capture(&launch_command(package), Some(TIMEOUT))?;
+
+    // monkey happily exits with status 0 when there's nothing to launch.
+    if output.stdout.contains("No activities found") {
+        eyre::bail!("{package} isn't installed or has nothing to launch");
+    }
+    output
+        .check()
+        .with_context(|| format!("Failed to launch {package}"))
+}
+
+/// Opens `url` in whichever app handles it on the TV.
+pub fn open(adb: &mut adb::Session, url: &str) -> Result<()> {
+    log::info!("Opening {url}");
+    let command = format!(
+        "am start -a android.intent.action.VIEW -d {}",
+        adb::quote(url)
+    );
+    let output = adb.capture(&command, Some(TIMEOUT))?;
+
+    // Like monkey, older versions of am exit with status 0 on errors.
+    if let Some(error) = output
+        .stdout
+        .lines()
+        .chain(output.stderr.lines())
+        .find(|line| line.starts_with("Error"))
+    {
+        eyre::bail!("Failed to open {url}: {error}");
+    }
+    output
+        .check()
+        .with_context(|| format!("Failed to open {url}"))
+}
+
+/// Lists the packages that have something to launch. Older versions of
+/// Android can't tell us that, in which case all installed packages are
+/// listed instead.
+pub fn launchable(adb: &mut adb::Session) -> Result<Vec<String>> {
+    let mut packages = match query_activities(adb) {
+        Ok(packages) => packages,
+        Err(e) => {
+            log::debug!("Failed to query launchable activities: {e:#}");
+            log::warn!("Can't tell which apps are launchable. Listing all packages instead");
+            installed(adb)?
+        }
+    };
+
+    packages.sort();
+    packages.dedup();
+    Ok(packages)
+}
+
+fn query_activities(adb: &mut adb::Session) -> Result<Vec<String>> {
+    let mut packages = Vec::new();
+
+    for category in LAUNCHER_CATEGORIES {
+        let command = format!(
+            "cmd package query-activities --brief -a android.intent.action.MAIN -c {category}"
+        );
+        let output = adb.capture(&command, Some(TIMEOUT))?;
+        output.check()?;
+        eyre::ensure!(
+            !output.stdout.contains("Unknown command"),
+            "query-activities isn't supported"
+        );
+
+        // The output has a line with package/activity for each activity, mixed
+        // in with other information.
+        packages.extend(output.stdout.lines().filter_map(|line| {
+            let line = line.trim();
+            match line.split_once('/') {
+                Some((package, _)) if !line.contains([' ', '=']) => Some(package.to_owned()),
+                _ => None,
+            }
+        }));
+    }
+
+    Ok(packages)
+}
+
+fn installed(adb: &mut adb::Session) -> Result<Vec<String>> {
+    let output = adb.capture("pm list packages", Some(TIMEOUT))?;
+    output.check().context("Failed to list packages")?;
+
+    Ok(output
+        .stdout
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("package:"))
+        .map(str::to_owned)
+        .collect())
+}
+
+pub fn list(adb: &mut adb::Session, aliases: &HashMap<String, String>) -> Result<()> {
+    for package in launchable(adb)? {
+        let mut names = aliases
+            .iter()
+            .filter(|(_, p)| **p == package)
+            .map(|(alias, _)| alias.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+
+        match &*names {
+            [] => println!("{package}"),
+            names => println!("{package} ({})", names.join(", ")),
+        }
+    }
+
+    Ok(())
+}
@@ -1,27 +1,63 @@
 use std::{
     collections::HashSet,
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
 };
 
-use clap::{Args, CommandFactory, Parser};
+use clap::{value_parser, ArgAction, Args, CommandFactory, Parser, Subcommand};
 use mac_address::MacAddress;
 
+use crate::{duration, idle, keycodes::Step, ping::Pinger, probe, retry, wol};
+
 /// TV power manager.
 #[derive(Debug, Parser)]
+// This is only parsed once, so the size of the service's options doesn't matter.
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Turn the TV on.
     On {
         #[command(flatten)]
         mac: MacAddr,
 
+        #[command(flatten)]
+        wol: Wol,
+
         #[command(flatten)]
         sockaddr: SockAddr,
+
+        #[command(flatten)]
+        idempotent: Idempotent,
+
+        #[command(flatten)]
+        ping: Ping,
     },
 
     /// Turn the TV off.
     Off {
         #[command(flatten)]
         sockaddr: SockAddr,
+
+        #[command(flatten)]
+        idempotent: Idempotent,
+
+        #[command(flatten)]
+        ping: Ping,
+    },
+
+    /// Turn the TV off if it's on, or on if it's off.
+    Toggle {
+        #[command(flatten)]
+        mac: MacAddr,
+
+        #[command(flatten)]
+        wol: Wol,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+
+        #[command(flatten)]
+        ping: Ping,
     },
 
     /// Runs in a service mode, turning the TV off when the computer is idle.
@@ -29,25 +65,266 @@ pub enum Command {
         #[command(flatten)]
         mac: MacAddr,
 
+        #[command(flatten)]
+        wol: Wol,
+
         #[command(flatten)]
         sockaddr: SockAddr,
 
+        #[command(flatten)]
+        ping: Ping,
+
+        #[command(flatten)]
+        retry: Retry,
+
+        /// How long the TV needs after being turned off before it can be
+        /// turned on again.
+        ///
+        /// If the TV should be turned on before then, the service waits until
+        /// the cooldown has passed.
+        #[arg(long, env, default_value = "10s", value_parser = parse_duration)]
+        cooldown: Duration,
+
+        /// What to watch to find out whether you're at the computer.
+        ///
+        /// This can be presence, which becomes idle when GNOME starts to dim
+        /// the screen, screensaver, which becomes active when the screen blanks
+        /// or locks, or logind, which is away when the login session is idle,
+        /// locked or in the background. Several can be given separated by
+        /// commas, in which case any one of them can turn the TV off. The
+        /// screensaver trigger works with GNOME as well as KDE Plasma, Xfce
+        /// and other desktops with a freedesktop screensaver, presence only
+        /// works with GNOME, and logind works anywhere. The default, auto, uses
+        /// presence on GNOME and screensaver elsewhere.
+        ///
+        /// As any one trigger can turn the TV off, combining them can only make
+        /// it turn off sooner, never keep it on for longer. For example,
+        /// presence,screensaver acts just like presence, as GNOME's presence
+        /// goes idle before the screensaver activates.
+        #[arg(long, env, value_enum, value_delimiter = ',', default_value = "auto")]
+        trigger: Vec<idle::Source>,
+
+        /// How long the session has to stay idle before the TV is turned off.
+        ///
+        /// Use this to keep the TV on if you come back shortly after GNOME
+        /// considers the session idle, like when it starts dimming the screen.
+        #[arg(long, env, default_value = "0s", value_parser = parse_duration)]
+        grace_period: Duration,
+
+        /// How to check whether the TV is on.
+        ///
+        /// This combines the probes icmp (the TV answers pings), tcp (the TV
+        /// accepts connections on the adb port), neighbour (the TV is in the
+        /// ARP table), drm (the graphics output is connected) and wakefulness
+        /// (Android says that it's awake) with and, or, else and parentheses.
+        /// a else b uses b if a can't tell.
+        #[arg(long, env, default_value = "icmp and wakefulness else drm")]
+        probe: probe::Expr,
+
         /// Which graphics output to watch to see if the TV is on.
         ///
-        /// You can list available outputs with the list-outputs command.
+        /// This is used by the drm probe. You can list available outputs with
+        /// the list-outputs command.
         #[arg(short, long, env)]
         output: Option<String>,
+
+        /// An app to launch every time the TV has been turned on.
+        ///
+        /// This can be a package name or an alias from the config file.
+        #[arg(long, env)]
+        launch: Option<String>,
+
+        /// A volume to set every time the TV has been turned on.
+        ///
+        /// You can see the TV's current volume with the volume get command.
+        #[arg(long, env)]
+        volume: Option<u32>,
+
+        /// An input to switch to every time the TV has been turned on.
+        ///
+        /// Use this if the TV sometimes wakes up on the wrong input. You can
+        /// list the TV's inputs with the inputs command.
+        #[arg(long, env)]
+        input: Option<String>,
+    },
+
+    /// Run a macro from the config file.
+    ///
+    /// Macros are defined in the config file with keys like macro.<name>. Their
+    /// values are comma-separated lists of steps. Steps can be key presses and
+    /// delays, like for the keycodes command, text:<text> to type text, or
+    /// launch:<package> to launch an app. Append *<n> to a step to repeat it.
+    /// Write two commas for a comma that doesn't end the step, like
+    /// text:Hello,, world.
+    Macro {
+        /// The name of the macro.
+        name: String,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Launch an app on the TV.
+    ///
+    /// Aliases for apps can be defined in the config file with keys like
+    /// app.<name>.
+    Launch {
+        /// The app's package name or alias, e.g. com.netflix.ninja.
+        app: String,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Open a URL on the TV, in whichever app handles it.
+    Open {
+        /// The URL to open.
+        url: String,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// List the apps that can be launched on the TV.
+    Apps {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Change or show the TV's volume.
+    Volume {
+        #[command(subcommand)]
+        command: VolumeCommand,
+    },
+
+    /// Type text on the TV.
+    ///
+    /// Non-ASCII text can only be typed if the ADBKeyBoard app is installed on
+    /// the TV and selected as its keyboard.
+    Text {
+        /// The text to type, or - to read it from stdin.
+        text: String,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Take a screenshot of the TV.
+    Screenshot {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+
+        /// The PNG file to save the screenshot to, or - to write it to stdout.
+        #[arg(short, long, default_value = "screenshot.png")]
+        output: PathBuf,
+    },
+
+    /// Switch the TV to another input, like an HDMI port.
+    Input {
+        /// The input's ID, or a part of it that only matches one input, like
+        /// Hdmi1 or HW5.
+        ///
+        /// You can list the TV's inputs with the inputs command.
+        input: String,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// List the TV's inputs.
+    Inputs {
+        #[command(flatten)]
+        sockaddr: SockAddr,
     },
 
     /// List video outputs.
     ListOutputs {},
 
+    /// Show whether the TV is on.
+    ///
+    /// Exits with status 0 if the TV is on, 3 if it's off, and 4 if it's
+    /// impossible to tell.
+    Status {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+
+        #[command(flatten)]
+        ping: Ping,
+
+        /// Which graphics output the TV is connected to.
+        ///
+        /// You can list available outputs with the list-outputs command.
+        #[arg(short, long, env)]
+        output: Option<String>,
+
+        /// Print the status as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Pair with the TV so that it accepts our adb connections.
+    ///
+    /// This asks the TV to trust this computer. Accept the prompt that shows up
+    /// on the TV.
+    Pair {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Send key presses to the TV.
     Keycodes {
         #[command(flatten)]
         sockaddr: SockAddr,
 
-        /// Keycodes to send.
-        keycodes: Vec<i32>,
+        /// Keys to press, and delays in between.
+        ///
+        /// Keys can be given as Android key code numbers or names, like HOME,
+        /// DPAD_UP or KEYCODE_MEDIA_PLAY_PAUSE. Names are case-insensitive.
+        /// Append :long for a long press, e.g. HOME:long. Delays are given like
+        /// 500ms or 2s.
+        #[arg(required_unless_present = "list")]
+        keys: Vec<Step>,
+
+        /// List the names of all key codes instead.
+        #[arg(long, conflicts_with = "keys")]
+        list: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum VolumeCommand {
+    /// Turn the volume up one step.
+    Up {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Turn the volume down one step.
+    Down {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Mute or unmute the TV.
+    Mute {
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Set the volume to a specific level.
+    Set {
+        /// The volume level. The range depends on the TV, but is usually 0 to
+        /// 15 or 0 to 100.
+        level: u32,
+
+        #[command(flatten)]
+        sockaddr: SockAddr,
+    },
+
+    /// Print the current volume level.
+    Get {
+        #[command(flatten)]
+        sockaddr: SockAddr,
     },
 }
 
@@ -73,6 +350,113 @@ pub struct SockAddr {
     pub port: u16,
 }
 
+#[derive(Debug, Args, Clone, Copy)]
+pub struct Idempotent {
+    /// Check the TV's state first, and don't toggle the power.
+    ///
+    /// With --idempotent=false, the power button is pressed like on the remote,
+    /// which toggles the TV on or off regardless of its current state.
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub idempotent: bool,
+}
+
+#[derive(Debug, Args, Clone, Copy)]
+pub struct Ping {
+    /// How long to wait for the TV to answer a ping, like 100ms or 1s.
+    #[arg(long, env, default_value = "100ms", value_parser = parse_duration)]
+    pub ping_timeout: Duration,
+
+    /// How many pings to send before deciding that the TV is unreachable.
+    #[arg(long, env, default_value_t = 1, value_parser = value_parser!(u16).range(1..))]
+    pub ping_count: u16,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct Wol {
+    /// Where to send Wake-on-LAN packets.
+    ///
+    /// By default, they're sent both to the broadcast address of the TV's
+    /// subnet and directly to the TV.
+    #[arg(long, env)]
+    pub wol_address: Option<IpAddr>,
+
+    /// The UDP port to send Wake-on-LAN packets to. This is usually 7 or 9.
+    #[arg(long, env, default_value_t = 9)]
+    pub wol_port: u16,
+
+    /// The network interface to send Wake-on-LAN packets from.
+    #[arg(long, env)]
+    pub wol_interface: Option<String>,
+
+    /// How many Wake-on-LAN packets to send each time.
+    #[arg(long, env, default_value_t = 3, value_parser = value_parser!(u32).range(1..))]
+    pub wol_repeat: u32,
+
+    /// The TV's SecureOn password, if it has one. This is written like a MAC
+    /// address.
+    #[arg(long, env)]
+    pub wol_password: Option<wol::Password>,
+}
+
+#[derive(Debug, Args, Clone, Copy)]
+pub struct Retry {
+    /// How many times to try turning the TV on or off before giving up.
+    #[arg(long, env, default_value_t = 10, value_parser = value_parser!(u32).range(1..))]
+    pub retry_attempts: u32,
+
+    /// How long to wait for the TV to turn on or off before trying again.
+    #[arg(long, env, default_value = "10s", value_parser = parse_duration)]
+    pub retry_timeout: Duration,
+
+    /// How long to wait before the first retry. This doubles for every retry,
+    /// with some randomness.
+    #[arg(long, env, default_value = "1s", value_parser = parse_duration)]
+    pub retry_backoff: Duration,
+
+    /// The longest time to wait between retries.
+    #[arg(long, env, default_value = "30s", value_parser = parse_duration)]
+    pub retry_max_backoff: Duration,
+
+    /// How long to keep trying to turn the TV on or off in total.
+    #[arg(long, env, default_value = "120s", value_parser = parse_duration)]
+    pub retry_deadline: Duration,
+}
+
+impl Wol {
+    pub fn into_options(self, mac: MacAddress, ip: IpAddr) -> wol::Options {
+        wol::Options {
+            mac,
+            ip,
+            target: self.wol_address,
+            port: self.wol_port,
+            interface: self.wol_interface,
+            repeat: self.wol_repeat,
+            password: self.wol_password,
+        }
+    }
+}
+
+impl Ping {
+    pub fn to_pinger(self) -> Pinger {
+        Pinger {
+            timeout: self.ping_timeout,
+            count: self.ping_count,
+        }
+    }
+}
+
+impl Retry {
+    pub fn to_policy(self) -> retry::Policy {
+        retry::Policy {
+            max_attempts: self.retry_attempts,
+            attempt_timeout: self.retry_timeout,
+            backoff: self.retry_backoff,
+            max_backoff: self.retry_max_backoff,
+            deadline: self.retry_deadline,
+        }
+    }
+}
+
 impl SockAddr {
     pub fn to_std(self) -> SocketAddr {
         SocketAddr::from((self.ip, self.port))
@@ -85,6 +469,10 @@ impl Command {
     }
 }
 
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    duration::parse(s).ok_or_else(|| format!("invalid duration {s:?}, use e.g. 500ms or 2s"))
+}
+
 fn env_vars_inner(command: &clap::Command) -> HashSet<String> {
     let mut envs: HashSet<_> = command
         .get_arguments()
@@ -1,12 +1,27 @@
-use std::{env, path::PathBuf};
+use std::{collections::HashMap, env, path::PathBuf};
 
 use color_eyre::{eyre::Context, Report, Result};
 
-use crate::cli::Command;
+use crate::{cli::Command, macros::Macro};
 
 const CONFIG_FILE: &str = "tv-power.conf";
 
-fn config_dir() -> PathBuf {
+/// Prefix of config keys that define macros.
+const MACRO_PREFIX: &str = "macro.";
+
+/// Prefix of config keys that define app aliases.
+const APP_PREFIX: &str = "app.";
+
+/// Settings from the config file that aren't command line arguments.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub macros: HashMap<String, Macro>,
+
+    /// Short names for apps, mapped to their package names.
+    pub apps: HashMap<String, String>,
+}
+
+pub fn config_dir() -> PathBuf {
     let config_dir = match env::var_os("XDG_CONFIG_DIR") {
         Some(d) => PathBuf::from(d),
         None => {
@@ -24,7 +39,12 @@ fn config_file() -> PathBuf {
     config_dir().join(CONFIG_FILE)
 }
 
-pub fn apply_env_overrides() -> Result<()> {
+/// Loads the config file.
+///
+/// Keys that correspond to command line arguments are set as environment
+/// variables, unless they're already set, so that clap picks them up. The rest
+/// are parsed and returned.
+pub fn load() -> Result<Config> {
     let file = config_file();
     log::debug!("Using config file path {file:?}");
 
@@ -32,7 +52,7 @@ pub fn apply_env_overrides() -> Result<()> {
         Ok(vars) => vars,
         Err(e) if e.not_found() => {
             log::debug!("Ignoring config file {file:?} as it doesn't exist");
-            return Ok(());
+            return Ok(Config::default());
         }
         Err(e) => {
             return Err(Report::new(e).wrap_err(format!("Failed to read config file {file:?}")))
@@ -40,11 +60,27 @@ pub fn apply_env_overrides() -> Result<()> {
     };
 
     let expected_vars = Command::env_vars();
+    let mut config = Config::default();
 
     for v in vars {
         let (var, val) = v.with_context(|| format!("Failed to parse config file {file:?}"))?;
         let var_upper = var.to_uppercase();
 
+        if let Some(name) = var.to_lowercase().strip_prefix(MACRO_PREFIX) {
+            let m = val
+                .parse()
+                .with_context(|| format!("Invalid macro {name} in config file {file:?}"))?;
+            log::debug!("Loaded macro {name}: {m:?}");
+            config.macros.insert(name.to_owned(), m);
+            continue;
+        }
+
+        if let Some(alias) = var.to_lowercase().strip_prefix(APP_PREFIX) {
+            log::debug!("Loaded app alias {alias}: {val}");
+            config.apps.insert(alias.to_owned(), val);
+            continue;
+        }
+
         if !expected_vars.contains(&var_upper) {
             log::warn!("Unexpected configuration key {var}");
 
@@ -52,7 +88,7 @@ pub fn apply_env_overrides() -> Result<()> {
             keys.sort();
             let keys = keys.join(", ").to_lowercase();
 
-            log::warn!("Valid keys are {keys} (case insensitive)");
+            log::warn!("Valid keys are {keys}, {MACRO_PREFIX}<name>, {APP_PREFIX}<name> (case insensitive)");
             continue;
         }
 
@@ -67,5 +103,5 @@ pub fn apply_env_overrides() -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(config)
 }
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+/// Parses a duration like `500ms`, `2s` or `2.5s`.
+pub fn parse(s: &str) -> Option<Duration> {
+    let s = s.to_lowercase();
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms.parse().ok().map(Duration::from_millis);
+    }
+
+    let secs: f64 = s.strip_suffix('s')?.parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        for (s, expected) in [
+            ("0s", Duration::ZERO),
+            ("500ms", Duration::from_millis(500)),
+            ("500MS", Duration::from_millis(500)),
+            ("2s", Duration::from_secs(2)),
+            ("2.5s", Duration::from_millis(2500)),
+            ("120s", Duration::from_secs(120)),
+        ] {
+            assert_eq!(parse(s), Some(expected), "{s}");
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for s in ["", "s", "ms", "2", "2m", "-1s", "1.5ms", "HOME", "infs"] {
+            assert_eq!(parse(s), None, "{s}");
+        }
+    }
+}
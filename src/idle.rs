@@ -0,0 +1,161 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use color_eyre::{eyre::Context, Result};
+use dbus::blocking::LocalConnection;
+
+use crate::{logind, presence, screensaver};
+
+/// Ways of finding out whether the user is at the computer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Source {
+    /// Presence if GNOME is running, and the screensaver otherwise.
+    Auto,
+
+    /// GNOME's session presence status, which becomes idle as soon as the
+    /// screen starts to dim.
+    Presence,
+
+    /// The screensaver, which becomes active when the screen blanks or locks.
+    /// This works with GNOME as well as desktops that implement the
+    /// freedesktop screensaver interface, like KDE Plasma and Xfce.
+    Screensaver,
+
+    /// The logind session, which is away when it's idle, locked or in the
+    /// background. This works with any desktop, and even on the console.
+    Logind,
+}
+
+impl Source {
+    /// Whether the source is on the system bus rather than the session bus.
+    pub fn uses_system_bus(self) -> bool {
+        self == Source::Logind
+    }
+
+    /// Calls `f` with whether the user is at the computer whenever that
+    /// changes, and returns whether they are now.
+    fn watch(
+        self,
+        dbus: &LocalConnection,
+        system_dbus: Option<&LocalConnection>,
+        f: impl FnMut(bool) + 'static,
+    ) -> Result<bool> {
+        match self {
+            Source::Auto => Self::detect(dbus)?.watch(dbus, system_dbus, f),
+            Source::Presence => {
+                let present = presence::is_present(dbus)?;
+                presence::watch(dbus, f)?;
+                Ok(present)
+            }
+            Source::Screensaver => {
+                let interface = screensaver::Interface::detect(dbus)?;
+                let present = interface.is_present(dbus)?;
+                interface.watch(dbus, f)?;
+                Ok(present)
+            }
+            Source::Logind => logind::watch(
+                system_dbus.expect("The system bus must be connected to use logind"),
+                f,
+            ),
+        }
+    }
+
+    /// Finds out which source [`Source::Auto`] should use, based on whether
+    /// GNOME's session manager is on the session bus.
+    fn detect(dbus: &LocalConnection) -> Result<Self> {
+        let source = if has_owner(dbus, "org.gnome.SessionManager")? {
+            Source::Presence
+        } else {
+            Source::Screensaver
+        };
+        log::info!("Using the {source} idle source");
+        Ok(source)
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Source::Auto => "auto",
+            Source::Presence => "presence",
+            Source::Screensaver => "screensaver",
+            Source::Logind => "logind",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Whether anything owns `name` on `dbus`.
+pub fn has_owner(dbus: &LocalConnection, name: &str) -> Result<bool> {
+    let proxy = dbus.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_secs(1),
+    );
+    let (has_owner,): (bool,) = proxy
+        .method_call("org.freedesktop.DBus", "NameHasOwner", (name,))
+        .with_context(|| format!("Failed to check whether {name} exists"))?;
+    Ok(has_owner)
+}
+
+/// What each source last said.
+struct State<F> {
+    present: HashMap<Source, bool>,
+    last: bool,
+    f: F,
+}
+
+impl<F: FnMut(bool)> State<F> {
+    fn set(&mut self, source: Source, present: bool) {
+        self.present.insert(source, present);
+
+        let all = self.present.values().all(|&present| present);
+        if all != self.last {
+            self.last = all;
+            (self.f)(all);
+        }
+    }
+}
+
+/// Watches the given sources, and calls `f` with whether the user is at the
+/// computer, first with the initial state and then whenever it changes.
+/// `system_dbus` has to be given if any of the sources use the system bus.
+///
+/// The user is only considered to be at the computer if all sources agree, so
+/// any one of them can turn the TV off.
+pub fn watch(
+    dbus: &LocalConnection,
+    system_dbus: Option<&LocalConnection>,
+    sources: &[Source],
+    f: impl FnMut(bool) + 'static,
+) -> Result<()> {
+    let state = Rc::new(RefCell::new(State {
+        present: HashMap::new(),
+        last: true,
+        f,
+    }));
+    for &source in sources {
+        let watcher = Rc::clone(&state);
+        let present = source
+            .watch(dbus, system_dbus, move |present| {
+                watcher.borrow_mut().set(source, present)
+            })
+            .with_context(|| format!("Failed to watch {source} idle source"))?;
+        state.borrow_mut().present.insert(source, present);
+    }
+
+    // Signals are only handled once the caller processes DBUS messages, so
+    // nothing has changed yet.
+    let mut state = state.borrow_mut();
+    let present = state.present.values().all(|&present| present);
+    state.last = present;
+    (state.f)(present);
+
+    Ok(())
+}
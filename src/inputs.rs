@@ -0,0 +1,206 @@
+use std::{fmt::Write, time::Duration};
+
+use color_eyre::{
+    eyre::{self, Context},
+    Help, Result,
+};
+
+use crate::{adb, apps};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The TV input framework's content URI for passthrough inputs, like HDMI
+/// ports. Opening it with an input's ID appended makes the TV switch to it.
+const PASSTHROUGH_URI: &str = "content://android.media.tv/passthrough/";
+
+/// Lists the IDs of the TV's inputs, like
+/// `com.droidlogic.tvinput/.services.Hdmi1InputService/HW5`.
+pub fn all(adb: &mut adb::Session) -> Result<Vec<String>> {
+    let output = adb.capture("dumpsys tv_input", Some(TIMEOUT))?;
+    output.check().context("Failed to list TV inputs")?;
+
+    let mut inputs = parse(&output.stdout);
+    eyre::ensure!(
+        !inputs.is_empty(),
+        "The TV didn't list any inputs. Does it have the TV input framework?"
+    );
+
+    inputs.sort();
+    inputs.dedup();
+    Ok(inputs)
+}
+
+/// Pulls the input IDs out of `dumpsys tv_input`. The layout of the output
+/// differs between Android versions, but the inputs are always printed as
+/// `TvInputInfo{id=<id>, pkg=...}`.
+fn parse(dumpsys: &str) -> Vec<String> {
+    dumpsys
+        .split("TvInputInfo")
+        .skip(1)
+        .filter_map(|s| {
+            let s = s.trim_start().strip_prefix('{')?.trim_start();
+            let id = s.strip_prefix("id=")?;
+            let end = id.find([',', '}'])?;
+            Some(id[..end].trim().to_owned())
+        })
+        .collect()
+}
+
+/// Finds the input that `name` refers to. This can be the full ID of an input,
+/// or any part of it that only matches one input, like `Hdmi1` or `HW5`.
+pub fn find(adb: &mut adb::Session, name: &str) -> Result<String> {
+    find_in(&all(adb)?, name)
+}
+
+fn find_in(inputs: &[String], name: &str) -> Result<String> {
+    if inputs.iter().any(|i| i == name) {
+        return Ok(name.to_owned());
+    }
+
+    let lower = name.to_lowercase();
+    let matches = inputs
+        .iter()
+        .filter(|i| i.to_lowercase().contains(&lower))
+        .collect::<Vec<_>>();
+
+    match &*matches {
+        [input] => Ok((*input).clone()),
+        [] => Err(eyre::eyre!("The TV has no input matching {name}"))
+            .suggestion("You can list the TV's inputs with tv-power inputs"),
+        _ => {
+            let matches = matches
+                .iter()
+                .map(|i| i.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            eyre::bail!("{name} matches multiple inputs: {matches}")
+        }
+    }
+}
+
+/// Switches the TV to the input that `name` refers to.
+pub fn switch(adb: &mut adb::Session, name: &str) -> Result<()> {
+    let input = find(adb, name)?;
+    log::info!("Switching to input {input}");
+
+    let uri = format!("{PASSTHROUGH_URI}{}", percent_encode(&input));
+    apps::open(adb, &uri).with_context(|| format!("Failed to switch to input {input}"))
+}
+
+pub fn list(adb: &mut adb::Session) -> Result<()> {
+    for input in all(adb)? {
+        println!("{input}");
+    }
+    Ok(())
+}
+
+/// Encodes `s` so that it can be used as a single URI path segment.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => write!(encoded, "%{b:02X}").unwrap(),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed `dumpsys tv_input` output from an Android 9 TV.
+    const DUMPSYS: &str = "\
+User Ids (Current user: 0):
+  0
+User State (0):
+  inputMap: inputId -> TvInputState
+    com.droidlogic.tvinput/.services.Hdmi1InputService/HW5: TvInputState{info=TvInputInfo{id=com.droidlogic.tvinput/.services.Hdmi1InputService/HW5, pkg=com.droidlogic.tvinput, service=.services.Hdmi1InputService}, state=0}
+    com.droidlogic.tvinput/.services.Hdmi2InputService/HW6: TvInputState{info=TvInputInfo{id=com.droidlogic.tvinput/.services.Hdmi2InputService/HW6, pkg=com.droidlogic.tvinput, service=.services.Hdmi2InputService}, state=2}
+    com.droidlogic.tvinput/.services.AV1InputService/HW1: TvInputState{info=TvInputInfo{id=com.droidlogic.tvinput/.services.AV1InputService/HW1, pkg=com.droidlogic.tvinput, service=.services.AV1InputService}, state=0}
+  packageSet:
+    com.droidlogic.tvinput
+  clientStateMap: ITvInputClient -> ClientState
+  mCallbacks: []
+  mainSessionToken: null
+";
+
+    fn inputs() -> Vec<String> {
+        parse(DUMPSYS)
+    }
+
+    #[test]
+    fn parse_dumpsys() {
+        assert_eq!(
+            inputs(),
+            [
+                "com.droidlogic.tvinput/.services.Hdmi1InputService/HW5",
+                "com.droidlogic.tvinput/.services.Hdmi2InputService/HW6",
+                "com.droidlogic.tvinput/.services.AV1InputService/HW1",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_no_inputs() {
+        assert!(parse("User Ids (Current user: 0):\n  0\n").is_empty());
+    }
+
+    #[test]
+    fn find_inputs() {
+        let inputs = inputs();
+        for (name, expected) in [
+            (
+                "com.droidlogic.tvinput/.services.Hdmi1InputService/HW5",
+                "com.droidlogic.tvinput/.services.Hdmi1InputService/HW5",
+            ),
+            (
+                "Hdmi1",
+                "com.droidlogic.tvinput/.services.Hdmi1InputService/HW5",
+            ),
+            (
+                "hdmi2",
+                "com.droidlogic.tvinput/.services.Hdmi2InputService/HW6",
+            ),
+            (
+                "HW1",
+                "com.droidlogic.tvinput/.services.AV1InputService/HW1",
+            ),
+        ] {
+            assert_eq!(find_in(&inputs, name).unwrap(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn find_ambiguous() {
+        let inputs = inputs();
+        for name in ["Hdmi", "HW", "droidlogic"] {
+            let err = find_in(&inputs, name).unwrap_err();
+            assert!(
+                err.to_string().contains("matches multiple inputs"),
+                "{name}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn find_missing() {
+        assert!(find_in(&inputs(), "Hdmi3").is_err());
+    }
+
+    #[test]
+    fn passthrough_uri() {
+        assert_eq!(
+            format!(
+                "{PASSTHROUGH_URI}{}",
+                percent_encode("com.droidlogic.tvinput/.services.Hdmi1InputService/HW5")
+            ),
+            "content://android.media.tv/passthrough/\
+             com.droidlogic.tvinput%2F.services.Hdmi1InputService%2FHW5"
+        );
+        assert_eq!(percent_encode("a b#c%d~e"), "a%20b%23c%25d~e");
+    }
+}
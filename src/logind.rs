@@ -0,0 +1,133 @@
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use color_eyre::{eyre::Context, Result};
+use dbus::{
+    arg::prop_cast,
+    blocking::{stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged, LocalConnection},
+    message::SignalArgs,
+};
+
+use generated::{Login1Manager, Login1Session, Login1SessionLock, Login1SessionUnlock};
+
+#[allow(dead_code, clippy::needless_borrow, clippy::bind_instead_of_map)]
+pub mod generated {
+    include!(concat!(env!("OUT_DIR"), "/logind.rs"));
+}
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+const BUS_NAME: &str = "org.freedesktop.login1";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+/// What logind says about our session.
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    /// Whether the session is in the foreground of its seat.
+    active: bool,
+
+    /// Whether the desktop says that the session is idle.
+    idle: bool,
+
+    /// Whether the screen is locked.
+    locked: bool,
+}
+
+impl Session {
+    fn is_present(self) -> bool {
+        self.active && !self.idle && !self.locked
+    }
+}
+
+struct Watcher<F> {
+    session: Session,
+    f: F,
+}
+
+impl<F: FnMut(bool)> Watcher<F> {
+    fn update(&mut self, change: impl FnOnce(&mut Session)) {
+        let was_present = self.session.is_present();
+        change(&mut self.session);
+        log::debug!("Logind session is now {:?}", self.session);
+
+        let present = self.session.is_present();
+        if present != was_present {
+            (self.f)(present);
+        }
+    }
+}
+
+/// Watches our logind session on the system bus, and calls `f` with whether
+/// the user is at the computer whenever that changes. Returns whether they are
+/// now.
+///
+/// The user is only considered to be at the computer if the session is active,
+/// not idle and not locked.
+pub fn watch(system_dbus: &LocalConnection, f: impl FnMut(bool) + 'static) -> Result<bool> {
+    let manager = system_dbus.with_proxy(BUS_NAME, "/org/freedesktop/login1", TIMEOUT);
+    // "auto" is the caller's session, or the user's graphical session if the
+    // caller isn't in one, like when running as a systemd user service.
+    let path = manager
+        .get_session("auto")
+        .context("Failed to find our logind session")?;
+
+    let proxy = system_dbus.with_proxy(BUS_NAME, &path, TIMEOUT);
+    let session = Session {
+        active: proxy.active()?,
+        idle: proxy.idle_hint()?,
+        locked: proxy.locked_hint()?,
+    };
+    log::debug!(
+        "Watching logind session {}, which is {session:?}",
+        proxy.id()?
+    );
+    let present = session.is_present();
+
+    let watcher = Rc::new(RefCell::new(Watcher { session, f }));
+
+    let on_change = Rc::clone(&watcher);
+    let match_rule = PropertiesPropertiesChanged::match_rule(None, Some(&path));
+    system_dbus.add_match(
+        match_rule.static_clone(),
+        move |signal: PropertiesPropertiesChanged, _dbus, _msg| {
+            if signal.interface_name != SESSION_INTERFACE {
+                return true;
+            }
+
+            let changed = &signal.changed_properties;
+            let active = prop_cast::<bool>(changed, "Active").copied();
+            let idle = prop_cast::<bool>(changed, "IdleHint").copied();
+            let locked = prop_cast::<bool>(changed, "LockedHint").copied();
+            on_change.borrow_mut().update(|session| {
+                session.active = active.unwrap_or(session.active);
+                session.idle = idle.unwrap_or(session.idle);
+                session.locked = locked.unwrap_or(session.locked);
+            });
+            true // Returning true keeps the match active.
+        },
+    )?;
+
+    let on_lock = Rc::clone(&watcher);
+    let match_rule = Login1SessionLock::match_rule(None, Some(&path));
+    system_dbus.add_match(
+        match_rule.static_clone(),
+        move |_: Login1SessionLock, _dbus, _msg| {
+            log::debug!("Logind asked the session to lock");
+            on_lock.borrow_mut().update(|session| session.locked = true);
+            true
+        },
+    )?;
+
+    let on_unlock = watcher;
+    let match_rule = Login1SessionUnlock::match_rule(None, Some(&path));
+    system_dbus.add_match(
+        match_rule.static_clone(),
+        move |_: Login1SessionUnlock, _dbus, _msg| {
+            log::debug!("Logind asked the session to unlock");
+            on_unlock
+                .borrow_mut()
+                .update(|session| session.locked = false);
+            true
+        },
+    )?;
+
+    Ok(present)
+}
@@ -0,0 +1,248 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+
+use crate::{adb, apps, config::Config, keycodes, text};
+
+/// A named sequence of actions, defined in the config file like
+///
+/// ```text
+/// macro.netflix = "HOME, DPAD_RIGHT*3, 500ms, ENTER"
+/// ```
+///
+/// Steps are separated by commas. A literal comma, like in `text:` steps, is
+/// written as two commas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// The step as it was written in the config file, for error messages.
+    pub source: String,
+    pub action: Action,
+    pub repeat: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// A key press or a delay.
+    Key(keycodes::Step),
+
+    /// Types text, e.g. `text:hello`.
+    Text(String),
+
+    /// Launches an app by its package name or alias, e.g.
+    /// `launch:com.netflix.ninja`.
+    Launch(String),
+}
+
+impl FromStr for Macro {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (i, step) in split_steps(s).iter().map(|step| step.trim()).enumerate() {
+            // The most likely reason for a broken step after some text is a
+            // comma in the text.
+            let after_text = matches!(
+                steps.last(),
+                Some(Step {
+                    action: Action::Text(_),
+                    ..
+                })
+            );
+            let step = step.parse().with_context(|| {
+                let hint = if after_text {
+                    ". Write ,, to type a comma in text"
+                } else {
+                    ""
+                };
+                format!("Invalid step {} ({step:?}){hint}", i + 1)
+            })?;
+            steps.push(step);
+        }
+        Ok(Self { steps })
+    }
+}
+
+/// Splits a macro definition at each comma, except for doubled commas, which
+/// stand for a single literal comma.
+fn split_steps(s: &str) -> Vec<String> {
+    let mut steps = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' if chars.next_if_eq(&',').is_some() => steps.last_mut().unwrap().push(','),
+            ',' => steps.push(String::new()),
+            c => steps.last_mut().unwrap().push(c),
+        }
+    }
+    steps
+}
+
+impl FromStr for Step {
+    type Err = eyre::Report;
+
+    /// Parses a step, optionally followed by `*n` to repeat it `n` times.
+    fn from_str(s: &str) -> Result<Self> {
+        eyre::ensure!(!s.is_empty(), "Empty step");
+
+        let (action, repeat) = match s.rsplit_once('*') {
+            Some((action, n)) if !s.starts_with("text:") => {
+                let n = n
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid repeat count {n:?}"))?;
+                eyre::ensure!(n > 0, "Repeat count must be at least 1");
+                (action.trim(), n)
+            }
+            _ => (s, 1),
+        };
+
+        let action = if let Some(text) = action.strip_prefix("text:") {
+            Action::Text(text.to_owned())
+        } else if let Some(package) = action.strip_prefix("launch:") {
+            Action::Launch(package.to_owned())
+        } else {
+            Action::Key(action.parse().map_err(eyre::Report::msg)?)
+        };
+
+        Ok(Self {
+            source: s.to_owned(),
+            action,
+            repeat,
+        })
+    }
+}
+
+impl Action {
+    fn run(&self, config: &Config, repeat: u32, adb: &mut adb::Session) -> Result<()> {
+        let command = match self {
+            Action::Key(step) => keycodes::to_shell_command(&[*step]),
+            // Text may need the ADB keyboard, which isn't a plain shell
+            // command. It's never repeated anyway.
+            Action::Text(t) => return text::send(adb, t),
+            Action::Launch(app) => apps::launch_command(apps::resolve(&config.apps, app)),
+        };
+        adb.shell(&vec![command; repeat as usize].join(" && "), None)
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Key(step) => write!(f, "{step}"),
+            Action::Text(text) => write!(f, "text:{text}"),
+            Action::Launch(package) => write!(f, "launch:{package}"),
+        }
+    }
+}
+
+/// Runs the macro called `name`.
+pub fn run(config: &Config, name: &str, adb: &mut adb::Session) -> Result<()> {
+    let Some(m) = config.macros.get(&name.to_lowercase()) else {
+        let mut names = config.macros.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        match &*names {
+            [] => eyre::bail!("Unknown macro {name}. There are no macros in the config file"),
+            names => eyre::bail!(
+                "Unknown macro {name}. Known macros are {}",
+                names.join(", ")
+            ),
+        }
+    };
+
+    for (i, step) in m.steps.iter().enumerate() {
+        log::debug!("Running step {} of macro {name}: {}", i + 1, step.action);
+        step.action
+            .run(config, step.repeat, adb)
+            .with_context(|| format!("Step {} ({}) of macro {name} failed", i + 1, step.source))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn actions(s: &str) -> Vec<(Action, u32)> {
+        let m: Macro = s.parse().unwrap();
+        m.steps
+            .into_iter()
+            .map(|step| (step.action, step.repeat))
+            .collect()
+    }
+
+    fn key(s: &str) -> Action {
+        Action::Key(s.parse().unwrap())
+    }
+
+    fn text(s: &str) -> Action {
+        Action::Text(s.to_owned())
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(
+            actions("HOME, DPAD_RIGHT*3, 500ms, launch:netflix ,ENTER"),
+            [
+                (key("HOME"), 1),
+                (key("DPAD_RIGHT"), 3),
+                (
+                    Action::Key(keycodes::Step::Wait(Duration::from_millis(500))),
+                    1
+                ),
+                (Action::Launch("netflix".to_owned()), 1),
+                (key("ENTER"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_steps() {
+        assert_eq!(
+            actions("text:Hello,, world, ENTER"),
+            [(text("Hello, world"), 1), (key("ENTER"), 1)]
+        );
+        assert_eq!(actions("text:a,,,,b"), [(text("a,,b"), 1)]);
+        assert_eq!(actions("text:,,"), [(text(","), 1)]);
+        // Text isn't repeated, so a * is typed as-is.
+        assert_eq!(actions("text:2*3"), [(text("2*3"), 1)]);
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(split_steps("a,b"), ["a", "b"]);
+        assert_eq!(split_steps("a,,b"), ["a,b"]);
+        assert_eq!(split_steps("a,,,b"), ["a,", "b"]);
+        assert_eq!(split_steps(""), [""]);
+    }
+
+    #[test]
+    fn errors() {
+        for (s, error) in [
+            ("HOME,", "Invalid step 2 (\"\")"),
+            ("", "Invalid step 1 (\"\")"),
+            (
+                "text:Hello, world",
+                "Invalid step 2 (\"world\"). Write ,, to type a comma in text",
+            ),
+            ("HOME*0", "Invalid step 1 (\"HOME*0\")"),
+            ("HOME*x", "Invalid step 1 (\"HOME*x\")"),
+        ] {
+            let err = s.parse::<Macro>().unwrap_err();
+            assert_eq!(err.to_string(), error, "{s}");
+        }
+    }
+}
@@ -1,46 +1,207 @@
-use std::{io::Write, net::SocketAddr, process, time::Duration};
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    process,
+    time::Duration,
+};
 
-use clap::Parser;
-use color_eyre::{eyre, Result};
-use dbus::{blocking::LocalConnection, message::SignalArgs};
+use clap::{CommandFactory, FromArgMatches};
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+use dbus::{
+    blocking::LocalConnection,
+    channel::{BusType, Channel},
+};
 use libsystemd::daemon::{self, NotifyState};
 use log::Level;
-use mac_address::MacAddress;
 
-use cli::Command;
-use power::PowerManager;
-use presence::{generated::SessionManagerPresenceStatusChanged, PresenceStatus};
+use cli::{Command, VolumeCommand};
+use power::{AfterPowerOn, PowerManager};
 
 mod adb;
+mod apps;
 mod cli;
 mod config;
+mod duration;
+mod idle;
+mod inputs;
+mod keycodes;
+mod logind;
+mod macros;
+mod netlink;
 mod outputs;
+mod ping;
 mod power;
 mod presence;
+mod probe;
+mod retry;
+mod screensaver;
+mod screenshot;
+mod status;
+mod text;
+mod volume;
+mod wakefulness;
+mod wol;
 
 fn main() -> Result<()> {
     init_logging()?;
 
-    if let Err(e) = config::apply_env_overrides() {
-        log::error!("{e:#}");
-        process::exit(2);
-    }
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{e:#}");
+            process::exit(2);
+        }
+    };
 
-    let cmd = Command::parse();
+    let cmd = parse_command();
     use Command::*;
     match cmd {
-        On { mac, sockaddr } => power::turn_on(sockaddr.to_std(), mac.mac),
-        Off { sockaddr } => power::turn_off(sockaddr.to_std()),
+        On {
+            mac,
+            wol,
+            sockaddr,
+            idempotent,
+            ping,
+        } => power::turn_on(
+            &mut adb::Session::new(sockaddr.to_std())?,
+            &wol.into_options(mac.mac, sockaddr.ip),
+            idempotent.idempotent,
+            ping.to_pinger(),
+        ),
+        Off {
+            sockaddr,
+            idempotent,
+            ping,
+        } => power::turn_off(
+            &mut adb::Session::new(sockaddr.to_std())?,
+            idempotent.idempotent,
+            ping.to_pinger(),
+        ),
+        Toggle {
+            mac,
+            wol,
+            sockaddr,
+            ping,
+        } => power::toggle(
+            &mut adb::Session::new(sockaddr.to_std())?,
+            &wol.into_options(mac.mac, sockaddr.ip),
+            ping.to_pinger(),
+        ),
         Service {
             mac,
+            wol,
             sockaddr,
+            ping,
+            retry,
+            cooldown,
+            grace_period,
+            trigger,
+            probe,
             output,
-        } => service(mac.mac, sockaddr.to_std(), output),
+            launch,
+            volume,
+            input,
+        } => {
+            let options = power::Options {
+                wol: wol.into_options(mac.mac, sockaddr.ip),
+                pinger: ping.to_pinger(),
+                probe,
+                output,
+                retry: retry.to_policy(),
+                cooldown,
+                grace_period,
+                after_power_on: AfterPowerOn {
+                    input,
+                    launch: launch.map(|app| apps::resolve(&config.apps, &app).to_owned()),
+                    volume,
+                },
+            };
+            service(sockaddr.to_std(), &trigger, options)
+        }
+        Macro { sockaddr, name } => {
+            macros::run(&config, &name, &mut adb::Session::new(sockaddr.to_std())?)
+        }
+        Launch { sockaddr, app } => apps::launch(
+            &mut adb::Session::new(sockaddr.to_std())?,
+            apps::resolve(&config.apps, &app),
+        ),
+        Open { sockaddr, url } => apps::open(&mut adb::Session::new(sockaddr.to_std())?, &url),
+        Apps { sockaddr } => apps::list(&mut adb::Session::new(sockaddr.to_std())?, &config.apps),
+        Volume { command } => match command {
+            VolumeCommand::Up { sockaddr } => {
+                volume::up(&mut adb::Session::new(sockaddr.to_std())?)
+            }
+            VolumeCommand::Down { sockaddr } => {
+                volume::down(&mut adb::Session::new(sockaddr.to_std())?)
+            }
+            VolumeCommand::Mute { sockaddr } => {
+                volume::mute(&mut adb::Session::new(sockaddr.to_std())?)
+            }
+            VolumeCommand::Set { level, sockaddr } => {
+                volume::set(&mut adb::Session::new(sockaddr.to_std())?, level)
+            }
+            VolumeCommand::Get { sockaddr } => {
+                volume::print(&mut adb::Session::new(sockaddr.to_std())?)
+            }
+        },
+        Text { text, sockaddr } => {
+            text::type_arg(&mut adb::Session::new(sockaddr.to_std())?, &text)
+        }
+        Screenshot { sockaddr, output } => {
+            screenshot::save(&mut adb::Session::new(sockaddr.to_std())?, &output)
+        }
+        Input { input, sockaddr } => {
+            inputs::switch(&mut adb::Session::new(sockaddr.to_std())?, &input)
+        }
+        Inputs { sockaddr } => inputs::list(&mut adb::Session::new(sockaddr.to_std())?),
         ListOutputs {} => outputs::list(),
-        Keycodes { sockaddr, keycodes } => adb::send_keycodes(sockaddr.to_std(), keycodes, None),
+        Status {
+            sockaddr,
+            ping,
+            output,
+            json,
+        } => process::exit(status::show(
+            sockaddr.to_std(),
+            output,
+            ping.to_pinger(),
+            json,
+        )?),
+        Pair { sockaddr } => adb::pair(sockaddr.to_std()),
+        // parse_command() has already handled --list.
+        Keycodes { sockaddr, keys, .. } => {
+            keycodes::send(&mut adb::Session::new(sockaddr.to_std())?, &keys, None)
+        }
     }
 }
 
+/// Parses the command line.
+///
+/// `keycodes --list` is handled here, as clap would otherwise insist on the
+/// TV's IP address, which isn't needed to list the key codes.
+fn parse_command() -> Command {
+    let mut matches = Command::command()
+        .mut_subcommand("keycodes", |keycodes| {
+            keycodes
+                .mut_arg("ip", |ip| {
+                    ip.required(false).required_unless_present("list")
+                })
+                // mut_arg() moves the argument to the end, so move the keys
+                // back behind the IP address.
+                .mut_arg("keys", |keys| keys)
+        })
+        .get_matches();
+    if let Some(("keycodes", args)) = matches.subcommand() {
+        if args.get_flag("list") {
+            keycodes::list();
+            process::exit(0);
+        }
+    }
+    Command::from_arg_matches_mut(&mut matches).unwrap_or_else(|e| e.exit())
+}
+
 fn init_logging() -> Result<()> {
     let use_systemd = systemd_journal_logger::connected_to_journal();
 
@@ -74,44 +235,72 @@ fn init_logging() -> Result<()> {
     Ok(())
 }
 
-fn service(mac: MacAddress, addr: SocketAddr, output: Option<String>) -> Result<()> {
+fn service(addr: SocketAddr, sources: &[idle::Source], options: power::Options) -> Result<()> {
     let dbus = connect_dbus()?;
-    let power_manager = PowerManager::new(mac, addr, &dbus, output)?;
-    let match_rule = SessionManagerPresenceStatusChanged::match_rule(None, None);
-
-    dbus.add_match(
-        match_rule,
-        move |signal: SessionManagerPresenceStatusChanged, _dbus, _msg| {
-            match PresenceStatus::try_from(signal.status) {
-                Ok(status) => {
-                    log::debug!("Got presence status {status:?}");
-                    power_manager.set_power(status.is_active());
-                }
-                Err(e) => {
-                    log::error!("Failed to parse presence status: {e}");
-                }
-            }
-            true // Returning true keeps the match active.
-        },
-    )?;
+    let system_dbus = if sources.iter().any(|s| s.uses_system_bus()) {
+        Some(connect(BusType::System).context("Failed to connect to the system DBUS")?)
+    } else {
+        None
+    };
+
+    let power_manager = PowerManager::new(addr, options)?;
+    idle::watch(&dbus, system_dbus.as_ref(), sources, move |present| {
+        power_manager.set_power(present)
+    })?;
 
     daemon::notify(
         false,
         &[NotifyState::Ready, NotifyState::Status("Idle".to_owned())],
     )?;
     log::info!("Listening to DBUS messages");
+    let buses: Vec<_> = [Some(&dbus), system_dbus.as_ref()]
+        .into_iter()
+        .flatten()
+        .collect();
     loop {
-        dbus.process(Duration::MAX)?;
+        process_dbus(&buses)?;
     }
 }
 
+/// Handles the messages that have arrived on any of the buses, waiting for
+/// one if there are none.
+fn process_dbus(buses: &[&LocalConnection]) -> Result<()> {
+    let mut handled = false;
+    for bus in buses {
+        while bus.process(Duration::ZERO)? {
+            handled = true;
+        }
+    }
+    if handled {
+        return Ok(());
+    }
+
+    let mut fds: Vec<_> = buses
+        .iter()
+        .map(|bus| libc::pollfd {
+            fd: bus.channel().watch().fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: fds is valid for its whole length, which is passed along.
+    let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e).context("Failed to wait for DBUS messages");
+        }
+    }
+    Ok(())
+}
+
 fn connect_dbus() -> Result<LocalConnection> {
     log::trace!("Connecting to DBUS");
     daemon::notify(false, &[NotifyState::Status("Waiting for DBUS".to_owned())])?;
 
     let attempts = 30;
     for attempt in 1..=attempts {
-        match LocalConnection::new_session() {
+        match connect(BusType::Session) {
             Ok(dbus) => return Ok(dbus),
             Err(e) => log::warn!("Failed to connect to DBUS: {e} (attempt {attempt}/{attempts})"),
         }
@@ -119,3 +308,10 @@ fn connect_dbus() -> Result<LocalConnection> {
 
     eyre::bail!("Failed to connect to DBUS after {attempts} attempts")
 }
+
+/// Connects to a bus such that [`process_dbus`] can wait for its messages.
+fn connect(bus: BusType) -> Result<LocalConnection, dbus::Error> {
+    let mut channel = Channel::get_private(bus)?;
+    channel.set_watch_enabled(true);
+    Ok(channel.into())
+}
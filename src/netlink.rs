@@ -0,0 +1,232 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use color_eyre::{eyre::Context, Result};
+
+/// Message types, from `linux/rtnetlink.h` and `linux/netlink.h`.
+const RTM_GETADDR: u16 = 22;
+const RTM_GETNEIGH: u16 = 30;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+/// Request flags.
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_DUMP: u16 = 0x300;
+
+const HEADER_LEN: usize = 16;
+
+/// Neighbour states, from `linux/neighbour.h`.
+pub const NUD_INCOMPLETE: u16 = 0x01;
+pub const NUD_REACHABLE: u16 = 0x02;
+pub const NUD_FAILED: u16 = 0x20;
+pub const NUD_PERMANENT: u16 = 0x80;
+
+/// Neighbour attribute holding the neighbour's IP address.
+const NDA_DST: u16 = 1;
+
+/// Address attributes, from `linux/if_addr.h`.
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_LABEL: u16 = 3;
+const IFA_BROADCAST: u16 = 4;
+
+/// An IPv4 address of one of this computer's network interfaces.
+#[derive(Debug, Clone)]
+pub struct Address {
+    pub interface: String,
+    pub local: Ipv4Addr,
+    pub prefix_len: u8,
+
+    /// The subnet's broadcast address, if it has one.
+    pub broadcast: Option<Ipv4Addr>,
+}
+
+impl Address {
+    /// Whether `ip` is in the same subnet as this address.
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        u32::from(self.local) & mask == u32::from(ip) & mask
+    }
+}
+
+/// A message from the kernel describing one entry in a table.
+#[derive(Debug)]
+pub struct Entry {
+    /// The fixed-size header that's specific to the message type, like an
+    /// `ndmsg` for neighbours.
+    pub header: Vec<u8>,
+
+    /// The entry's attributes, as type and value.
+    pub attrs: Vec<(u16, Vec<u8>)>,
+}
+
+impl Entry {
+    pub fn attr(&self, kind: u16) -> Option<&[u8]> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+/// Dumps one of the kernel's tables, like the neighbour table for
+/// [`RTM_GETNEIGH`]. `header` is the type-specific request header, which is
+/// also the size of the header of each returned entry.
+pub fn dump(kind: u16, header: &[u8]) -> Result<Vec<Entry>> {
+    dump_inner(kind, header).with_context(|| format!("Netlink request {kind} failed"))
+}
+
+fn dump_inner(kind: u16, header: &[u8]) -> io::Result<Vec<Entry>> {
+    // SAFETY: socket doesn't touch memory, and we take ownership of the file
+    // descriptor right away.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a freshly opened socket that nothing else owns.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let seq = 1u32;
+    let len = HEADER_LEN + align(header.len());
+    let mut request = Vec::with_capacity(len);
+    request.extend_from_slice(&(len as u32).to_ne_bytes());
+    request.extend_from_slice(&kind.to_ne_bytes());
+    request.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    request.extend_from_slice(&seq.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    request.extend_from_slice(header);
+    request.resize(len, 0);
+
+    // SAFETY: request is valid for its whole length.
+    let sent = unsafe { libc::send(socket.as_raw_fd(), request.as_ptr().cast(), len, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut entries = Vec::new();
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        // SAFETY: buf is valid for its whole length.
+        let n = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut data = &buf[..n as usize];
+        while data.len() >= HEADER_LEN {
+            let msg_len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+            let msg_kind = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+            let msg_seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+            if msg_len < HEADER_LEN || msg_len > data.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Truncated netlink message",
+                ));
+            }
+            let body = &data[HEADER_LEN..msg_len];
+            data = &data[align(msg_len).min(data.len())..];
+
+            if msg_seq != seq {
+                continue;
+            }
+
+            match msg_kind {
+                NLMSG_DONE => return Ok(entries),
+                NLMSG_ERROR => {
+                    let errno = body
+                        .get(0..4)
+                        .map(|e| i32::from_ne_bytes(e.try_into().unwrap()))
+                        .unwrap_or(0);
+                    if errno != 0 {
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+                }
+                _ if body.len() >= header.len() => entries.push(Entry {
+                    header: body[..header.len()].to_vec(),
+                    attrs: parse_attrs(body.get(align(header.len())..).unwrap_or_default()),
+                }),
+                _ => log::debug!("Ignoring short netlink message"),
+            }
+        }
+    }
+}
+
+/// Looks up `ip`'s state in the kernel's neighbour table.
+pub fn neighbour_state(ip: IpAddr) -> Result<Option<u16>> {
+    let (family, octets) = match ip {
+        IpAddr::V4(ip) => (libc::AF_INET, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (libc::AF_INET6, ip.octets().to_vec()),
+    };
+
+    // An ndmsg: family, padding, interface index, state, flags and type.
+    let mut header = [0u8; 12];
+    header[0] = family as u8;
+
+    let state = dump(RTM_GETNEIGH, &header)?
+        .into_iter()
+        .find(|e| e.attr(NDA_DST) == Some(&octets))
+        .map(|e| u16::from_ne_bytes(e.header[8..10].try_into().unwrap()));
+    Ok(state)
+}
+
+/// The IPv4 addresses of this computer's network interfaces.
+pub fn ipv4_addresses() -> Result<Vec<Address>> {
+    // An ifaddrmsg: family, prefix length, flags, scope and interface index.
+    let mut header = [0u8; 8];
+    header[0] = libc::AF_INET as u8;
+
+    let addresses = dump(RTM_GETADDR, &header)?
+        .into_iter()
+        .filter_map(|e| {
+            let ip = |attr| -> Option<Ipv4Addr> {
+                let octets: [u8; 4] = e.attr(attr)?.try_into().ok()?;
+                Some(octets.into())
+            };
+            let interface = e
+                .attr(IFA_LABEL)
+                .map(|l| String::from_utf8_lossy(l).trim_end_matches('\0').to_owned())
+                .unwrap_or_default();
+
+            Some(Address {
+                interface,
+                local: ip(IFA_LOCAL).or_else(|| ip(IFA_ADDRESS))?,
+                prefix_len: e.header[1],
+                broadcast: ip(IFA_BROADCAST),
+            })
+        })
+        .collect();
+    Ok(addresses)
+}
+
+/// Parses `rtattr`s, which each have a two byte length and type followed by
+/// the value.
+fn parse_attrs(mut data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut attrs = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes(data[0..2].try_into().unwrap()) as usize;
+        // The top bits are flags.
+        let kind = u16::from_ne_bytes(data[2..4].try_into().unwrap()) & 0x3fff;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        attrs.push((kind, data[4..len].to_vec()));
+        data = &data[align(len).min(data.len())..];
+    }
+    attrs
+}
+
+/// Netlink pads everything to 4 bytes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
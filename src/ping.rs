@@ -0,0 +1,234 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    os::fd::FromRawFd,
+    sync::Once,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+
+/// ICMP message types.
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Checks whether the TV is reachable over the network.
+#[derive(Debug, Clone, Copy)]
+pub struct Pinger {
+    /// How long to wait for each reply.
+    pub timeout: Duration,
+
+    /// How many times to try before giving up.
+    pub count: u16,
+}
+
+impl Pinger {
+    /// Pings `addr`'s IP address. If we aren't allowed to send pings, this
+    /// instead checks whether anything answers on `addr`'s port.
+    pub fn ping(&self, addr: SocketAddr) -> Result<bool> {
+        let reachable = match self.ping_icmp(addr.ip()) {
+            Ok(reachable) => reachable,
+            Err(e) if matches!(e.kind(), ErrorKind::PermissionDenied) => {
+                static WARN: Once = Once::new();
+                WARN.call_once(|| {
+                    log::warn!(
+                        "Not allowed to send pings. Connecting to {addr} instead. \
+                         Add your group to net.ipv4.ping_group_range to allow pings"
+                    )
+                });
+                self.ping_tcp(addr)
+            }
+            Err(e) => {
+                log::debug!("Failed to ping {}: {e}", addr.ip());
+                false
+            }
+        };
+
+        log::trace!(
+            "{} is {}",
+            addr.ip(),
+            if reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            }
+        );
+        Ok(reachable)
+    }
+
+    /// Sends ICMP echo requests with an unprivileged ICMP socket.
+    ///
+    /// The kernel fills in the identifier and checksum of each request, and
+    /// only gives us back replies to our own requests.
+    fn ping_icmp(&self, ip: IpAddr) -> io::Result<bool> {
+        let socket = icmp_socket(ip)?;
+        socket.connect((ip, 0))?;
+
+        let (request, reply) = match ip {
+            IpAddr::V4(_) => (ICMP_ECHO_REQUEST, ICMP_ECHO_REPLY),
+            IpAddr::V6(_) => (ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY),
+        };
+
+        for seq in 1..=self.count {
+            let mut packet = [0; 16];
+            packet[0] = request;
+            packet[6..8].copy_from_slice(&seq.to_be_bytes());
+            packet[8..].copy_from_slice(b"tv-power");
+
+            match socket.send(&packet) {
+                Ok(_) => {}
+                // The kernel tells us right away if there's no route to the TV.
+                Err(e) if is_unreachable(&e) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+
+            let deadline = Instant::now() + self.timeout;
+            let mut buf = [0; 64];
+            while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+                match socket.recv(&mut buf) {
+                    Ok(n) if n >= 8 && buf[0] == reply && buf[6..8] == seq.to_be_bytes() => {
+                        return Ok(true)
+                    }
+                    Ok(_) => continue,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        break
+                    }
+                    Err(e) if is_unreachable(&e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            log::trace!("No ping reply from {ip} (attempt {seq}/{})", self.count);
+        }
+
+        Ok(false)
+    }
+
+    /// Tries to connect to `addr`. Any answer at all, even a refused
+    /// connection, means that the TV is there.
+    pub fn ping_tcp(&self, addr: SocketAddr) -> bool {
+        for attempt in 1..=self.count {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(_) => return true,
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => return true,
+                Err(e) => log::trace!(
+                    "Failed to connect to {addr}: {e} (attempt {attempt}/{})",
+                    self.count
+                ),
+            }
+        }
+        false
+    }
+}
+
+/// Opens an unprivileged ICMP socket. This fails with a permission error
+/// unless the user is in `net.ipv4.ping_group_range`, which also applies to
+/// IPv6.
+fn icmp_socket(ip: IpAddr) -> io::Result<UdpSocket> {
+    let (domain, protocol) = match ip {
+        IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+        IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+    };
+
+    // SAFETY: socket doesn't touch memory, and we take ownership of the file
+    // descriptor right away.
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // ICMP datagram sockets are used with the same calls as UDP sockets.
+    // SAFETY: fd is a freshly opened socket that nothing else owns.
+    Ok(unsafe { UdpSocket::from_raw_fd(fd) })
+}
+
+fn is_unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EHOSTDOWN | libc::ENETDOWN)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+    use super::*;
+
+    const PINGER: Pinger = Pinger {
+        timeout: Duration::from_secs(1),
+        count: 2,
+    };
+
+    #[test]
+    fn tcp_open_port() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(PINGER.ping_tcp(listener.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn tcp_closed_port() {
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // The listener is closed now, so the connection is refused, which still
+        // means that the host is there.
+        assert!(PINGER.ping_tcp(addr));
+    }
+
+    #[test]
+    fn ping_falls_back_to_tcp() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(PINGER.ping(listener.local_addr().unwrap()).unwrap());
+    }
+
+    /// Pings `ip`, or returns `None` if this user isn't allowed to send pings.
+    fn ping_icmp(ip: IpAddr) -> Option<bool> {
+        match PINGER.ping_icmp(ip) {
+            Ok(reachable) => Some(reachable),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("Skipping ICMP test, as pings aren't allowed: {e}");
+                None
+            }
+            Err(e) => panic!("Failed to ping {ip}: {e}"),
+        }
+    }
+
+    #[test]
+    fn icmp_v4() {
+        if let Some(reachable) = ping_icmp(Ipv4Addr::LOCALHOST.into()) {
+            assert!(reachable);
+        }
+    }
+
+    #[test]
+    fn icmp_v6() {
+        if UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+            eprintln!("Skipping ICMPv6 test, as there's no IPv6 loopback");
+            return;
+        }
+        if let Some(reachable) = ping_icmp(Ipv6Addr::LOCALHOST.into()) {
+            assert!(reachable);
+        }
+    }
+
+    #[test]
+    fn unreachable_errors() {
+        for errno in [
+            libc::EHOSTUNREACH,
+            libc::ENETUNREACH,
+            libc::EHOSTDOWN,
+            libc::ENETDOWN,
+        ] {
+            assert!(is_unreachable(&io::Error::from_raw_os_error(errno)));
+        }
+        for errno in [libc::EPERM, libc::ECONNREFUSED, libc::ETIMEDOUT] {
+            assert!(!is_unreachable(&io::Error::from_raw_os_error(errno)));
+        }
+    }
+}
@@ -1,78 +1,121 @@
 use std::{
-    net::{IpAddr, SocketAddr},
-    os::unix::process::ExitStatusExt,
-    process::{Command, Stdio},
+    net::SocketAddr,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use color_eyre::{
     eyre::{self, Context},
-    Result,
+    Result, Section,
 };
-use crossbeam::channel::Sender;
-use dbus::blocking::LocalConnection;
+use crossbeam::channel::{Receiver, RecvError, RecvTimeoutError, Sender};
 use libsystemd::daemon::{self, NotifyState};
-use mac_address::MacAddress;
-use nix::sys::signal::Signal;
 
 use crate::{
-    adb, outputs,
-    presence::{generated::SessionManagerPresence, PresenceStatus},
+    adb, apps, inputs, outputs,
+    ping::Pinger,
+    probe::{self, Probe},
+    retry, volume,
+    wakefulness::Wakefulness,
+    wol,
 };
 
 pub struct PowerManager {
     sender: Sender<bool>,
 }
 
-impl PowerManager {
-    pub fn new(
-        mac: MacAddress,
-        addr: SocketAddr,
-        dbus: &LocalConnection,
-        output: Option<String>,
-    ) -> Result<Self> {
-        let proxy = dbus.with_proxy(
-            "org.gnome.SessionManager",
-            "/org/gnome/SessionManager/Presence",
-            Duration::from_secs(1),
-        );
+/// Things the service does every time it has turned the TV on.
+#[derive(Debug, Default)]
+pub struct AfterPowerOn {
+    /// Input to switch to.
+    pub input: Option<String>,
 
-        let last_status = proxy
-            .status()
-            .context("Failed to get initial presence status over DBUS")?;
-        let last_status = PresenceStatus::try_from(last_status)
-            .context("Failed to parse initial presence status")?;
-        log::debug!("Got initial presence status {last_status:?}");
+    /// Package name of an app to launch.
+    pub launch: Option<String>,
 
-        let output = find_output(output).context("Failed to find graphical output")?;
-        let last_active = last_status.is_active();
-        let (sender, receiver) = crossbeam::channel::unbounded();
-        thread::spawn(move || {
-            let mut last_active = last_active;
+    /// Volume to restore.
+    pub volume: Option<u32>,
+}
 
-            while let Ok(power_on) = receiver.recv() {
-                if power_on == last_active {
-                    continue;
-                }
-                last_active = power_on;
-
-                let onoff = if power_on { "on" } else { "off" };
-                let status = format!("Turning TV {onoff}");
-                log::info!("{}", status);
-                daemon::notify(false, &[NotifyState::Status(status)]).ok();
-
-                while let Err(e) = turn_on_or_off_wait(power_on, addr, mac, &output) {
-                    log::error!("Failed to turn TV {onoff}: {e}");
-                    daemon::notify(
-                        false,
-                        &[NotifyState::Status(format!("Retrying TV power-{onoff}"))],
-                    )
-                    .ok();
-                }
+impl AfterPowerOn {
+    /// Runs the actions. The TV is already on at this point, so failures are
+    /// only logged rather than retrying the whole power-on.
+    fn run(&self, adb: &mut adb::Session) {
+        if let Some(input) = &self.input {
+            if let Err(e) = inputs::switch(adb, input) {
+                log::error!("Failed to switch to input {input}: {e}");
+            }
+        }
 
-                daemon::notify(false, &[NotifyState::Status("Idle".to_owned())]).ok();
+        if let Some(level) = self.volume {
+            if let Err(e) = volume::set(adb, level) {
+                log::error!("Failed to restore volume: {e}");
             }
+        }
+
+        if let Some(package) = &self.launch {
+            if let Err(e) = apps::launch(adb, package) {
+                log::error!("Failed to launch {package}: {e}");
+            }
+        }
+    }
+}
+
+/// How the service turns the TV on and off.
+#[derive(Debug)]
+pub struct Options {
+    pub wol: wol::Options,
+    pub pinger: Pinger,
+
+    /// How to check whether the TV is on.
+    pub probe: probe::Expr,
+
+    /// The graphical output the TV is connected to, for the DRM probe.
+    pub output: Option<String>,
+
+    pub retry: retry::Policy,
+
+    /// How long the TV needs after being turned off before it can be turned
+    /// on again.
+    pub cooldown: Duration,
+
+    /// How long to wait before turning the TV off, in case the user comes
+    /// back.
+    pub grace_period: Duration,
+
+    pub after_power_on: AfterPowerOn,
+}
+
+impl PowerManager {
+    /// Starts the worker thread. The first call to [`Self::set_power`] tells
+    /// it whether the TV is on to begin with, and doesn't turn it on or off.
+    pub fn new(addr: SocketAddr, options: Options) -> Result<Self> {
+        // The output is only needed for the DRM probe, and it's fine if there
+        // isn't one otherwise.
+        let output = if options.probe.uses(probe::Kind::Drm) {
+            Some(find_output(options.output.clone()).context("Failed to find graphical output")?)
+        } else {
+            None
+        };
+        log::debug!("Checking whether the TV is on with {}", options.probe);
+        let probe = options.probe.build(options.pinger, output.as_deref());
+        let adb = adb::Session::new(addr)?;
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        thread::spawn(move || {
+            let Ok(power_on) = receiver.recv() else {
+                return;
+            };
+            let worker = Worker {
+                adb,
+                options,
+                probe,
+                receiver,
+                desired: power_on,
+                current: power_on,
+                last_off: None,
+                transitions: 0,
+            };
+            worker.run();
         });
         Ok(Self { sender })
     }
@@ -84,10 +127,38 @@ impl PowerManager {
     }
 }
 
-pub fn turn_on(addr: SocketAddr, mac: MacAddress) -> Result<()> {
-    if ping_tv(addr.ip())? {
+/// Android key codes.
+const KEYCODE_POWER: i32 = 26;
+const KEYCODE_SLEEP: i32 = 223;
+const KEYCODE_WAKEUP: i32 = 224;
+
+/// Turns the TV on.
+///
+/// If `idempotent` is set, this checks whether the TV is already on first and
+/// wakes it with a key that doesn't toggle the power. Otherwise, this presses
+/// the power button like the remote does.
+pub fn turn_on(
+    adb: &mut adb::Session,
+    wol: &wol::Options,
+    idempotent: bool,
+    pinger: Pinger,
+) -> Result<()> {
+    if pinger.ping(adb.addr())? {
         log::debug!("TV responds to ping. Trying to turn it on via adb");
-        match send_power_key(addr) {
+        let res = if idempotent {
+            match Wakefulness::query(adb) {
+                Wakefulness::Awake => {
+                    log::info!("TV is already on");
+                    return Ok(());
+                }
+                Wakefulness::Unreachable => Err(eyre::eyre!("TV is unreachable over adb")),
+                Wakefulness::Asleep | Wakefulness::Dozing => send_key(adb, KEYCODE_WAKEUP),
+            }
+        } else {
+            send_power_key(adb)
+        };
+
+        match res {
             Ok(()) => {
                 log::debug!("Turning on via adb succeeded");
                 return Ok(());
@@ -102,89 +173,345 @@ pub fn turn_on(addr: SocketAddr, mac: MacAddress) -> Result<()> {
         }
     }
 
-    let mac = wol::MacAddr(mac.bytes());
-
     // Send a magic wake-on-LAN packet to the TV to wake it. Actually, a
     // wake-on-WLAN packet, as it's on WiFi.
-    wol::send_wol(mac, None, None)?;
+    wol::send(wol)
+}
+
+/// Turns the TV off.
+///
+/// If `idempotent` is set, this does nothing if the TV is already off, and
+/// uses a key that puts the TV to sleep rather than toggling the power.
+/// Otherwise, this presses the power button like the remote does.
+///
+/// If the TV can't be reached over adb, `pinger` decides whether it's off.
+pub fn turn_off(adb: &mut adb::Session, idempotent: bool, pinger: Pinger) -> Result<()> {
+    if !idempotent {
+        return send_power_key(adb);
+    }
 
-    Ok(())
+    match Wakefulness::query(adb) {
+        Wakefulness::Awake => send_key(adb, KEYCODE_SLEEP),
+        Wakefulness::Asleep | Wakefulness::Dozing => {
+            log::info!("TV is already off");
+            Ok(())
+        }
+        Wakefulness::Unreachable => {
+            // The TV turns off its WiFi along with the screen, so if it still
+            // answers pings, it's on and adb is just down.
+            if !pinger.ping(adb.addr())? {
+                log::info!("TV doesn't respond to ping, so it's already off");
+                return Ok(());
+            }
+            log::warn!("TV responds to ping but not to adb. Trying to turn it off anyway");
+            send_key(adb, KEYCODE_SLEEP)
+                .context("TV is on, but unreachable over adb")
+                .suggestion("Make sure that network debugging is still enabled on the TV")
+        }
+    }
 }
 
-pub fn turn_off(addr: SocketAddr) -> Result<()> {
-    send_power_key(addr)
+/// Turns the TV off if it's on, or on if it's off.
+pub fn toggle(adb: &mut adb::Session, wol: &wol::Options, pinger: Pinger) -> Result<()> {
+    match Wakefulness::query(adb) {
+        Wakefulness::Awake => turn_off(adb, true, pinger),
+        _ => turn_on(adb, wol, true, pinger),
+    }
 }
 
-fn turn_on_wait(addr: SocketAddr, mac: MacAddress, output: &str) -> Result<()> {
-    log::info!("Turning on the TV");
-    loop {
-        log::debug!("Sending WoL packet");
-        turn_on(addr, mac)?;
+/// How often to check whether the TV has turned on or off.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The state of the thread that actually turns the TV on and off.
+///
+/// Only the latest desired state matters. Messages that arrive while the TV is
+/// being turned on or off are checked for while waiting, and if the TV should
+/// now be in the other state, the transition is abandoned and a new one starts
+/// right away.
+struct Worker {
+    adb: adb::Session,
+    options: Options,
+    probe: Box<dyn Probe>,
+    receiver: Receiver<bool>,
+
+    /// Whether the TV should be on, according to the latest message.
+    desired: bool,
+
+    /// Whether the TV was on after the last transition.
+    current: bool,
+
+    /// When the TV was last turned off, for the cooldown.
+    last_off: Option<Instant>,
+
+    /// How many transitions have been started, used to tell them apart in
+    /// logs.
+    transitions: u64,
+}
+
+/// How a transition ended.
+enum Outcome {
+    Done,
+
+    /// The desired state changed before the transition finished.
+    Interrupted,
+
+    GaveUp(eyre::Report),
+}
+
+impl Worker {
+    fn run(mut self) {
+        loop {
+            if self.desired == self.current {
+                match self.receiver.recv() {
+                    Ok(power_on) => self.update(power_on),
+                    Err(RecvError) => return,
+                }
+                continue;
+            }
+
+            if !self.desired && !self.wait_for_grace_period() {
+                continue;
+            }
+
+            self.transitions += 1;
+            let id = self.transitions;
+            let on = self.desired;
+            let onoff = if on { "on" } else { "off" };
+            let status = format!("Turning TV {onoff}");
+            log::info!("[#{id}] {status}");
+            daemon::notify(false, &[NotifyState::Status(status)]).ok();
 
-        thread::sleep(Duration::from_millis(100));
+            match self.turn_on_or_off_wait(id, on) {
+                Outcome::Done => {
+                    log::info!("[#{id}] Turned TV {onoff}");
+                    self.current = on;
+                    if on {
+                        self.options.after_power_on.run(&mut self.adb);
+                    } else {
+                        self.last_off = Some(Instant::now());
+                    }
+                }
+                Outcome::Interrupted => {
+                    log::info!(
+                        "[#{id}] Stopped turning TV {onoff}, as it should now be {}",
+                        if self.desired { "on" } else { "off" }
+                    );
+                    // We don't know what state the TV was left in, but the
+                    // next transition will take it to the desired state
+                    // anyway.
+                    self.current = on;
+                    continue;
+                }
+                Outcome::GaveUp(e) => {
+                    log::error!("[#{id}] {e}");
+                    // Don't try again until the desired state changes.
+                    self.current = on;
+                    let status = format!("Gave up turning TV {onoff}");
+                    daemon::notify(false, &[NotifyState::Status(status)]).ok();
+                    continue;
+                }
+            }
 
-        if tv_is_on(addr.ip(), output)? {
-            log::info!("Turned on the TV");
-            return Ok(());
+            self.notify_idle();
         }
+    }
 
-        log::debug!("TV is not yet on, retrying...")
+    fn notify_idle(&self) {
+        let adb_state = if self.adb.is_connected() {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        let status = format!("Idle (adb {adb_state})");
+        daemon::notify(false, &[NotifyState::Status(status)]).ok();
     }
-}
 
-fn turn_off_wait(addr: SocketAddr, output: &str) -> Result<()> {
-    log::info!("Turning off the TV");
-    turn_off(addr)?;
-    log::debug!("Waiting for TV to turn off...");
+    /// Waits for the grace period before turning the TV off. Returns false if
+    /// the TV should be on again before it has passed.
+    fn wait_for_grace_period(&mut self) -> bool {
+        let grace_period = self.options.grace_period;
+        if grace_period.is_zero() {
+            return true;
+        }
 
-    loop {
-        thread::sleep(Duration::from_millis(100));
+        log::info!("Turning TV off in {grace_period:?} unless the session becomes active again");
+        let status = format!("Turning TV off in {}s", grace_period.as_secs_f32().ceil());
+        daemon::notify(false, &[NotifyState::Status(status)]).ok();
 
-        if !tv_is_on(addr.ip(), output)? {
-            log::info!("Turned off the TV");
-            return Ok(());
+        let start = Instant::now();
+        if self.sleep(false, grace_period) {
+            return true;
         }
 
-        log::debug!("TV is not yet off...");
+        log::info!(
+            "Not turning TV off, as the session became active again after {:.1?}",
+            start.elapsed()
+        );
+        self.notify_idle();
+        false
     }
-}
 
-fn turn_on_or_off_wait(on: bool, addr: SocketAddr, mac: MacAddress, output: &str) -> Result<()> {
-    if on {
-        turn_on_wait(addr, mac, output)
-    } else {
-        turn_off_wait(addr, output)
+    /// Records a message, along with any others that have queued up, as only
+    /// the latest one matters.
+    fn update(&mut self, power_on: bool) {
+        self.desired = self.receiver.try_iter().last().unwrap_or(power_on);
+        log::debug!("TV should be {}", if self.desired { "on" } else { "off" });
     }
-}
 
-pub fn send_power_key(addr: SocketAddr) -> Result<()> {
-    adb::send_keycode(addr, 26, Some(Duration::from_secs(1)))
-}
+    /// Sleeps for `duration`, or until a message says that the TV should no
+    /// longer be `on`. Returns false in the latter case.
+    fn sleep(&mut self, on: bool, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(timeout) {
+                Ok(power_on) => {
+                    self.update(power_on);
+                    if self.desired != on {
+                        return false;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(timeout);
+                    return true;
+                }
+            }
+        }
+    }
 
-fn ping_tv(ip: IpAddr) -> Result<bool> {
-    // Send one ping with a 200ms timeout.
-    let status = Command::new("ping")
-        .args(["-q", "-c", "1", "-W", "0.1"])
-        .arg(ip.to_string())
-        .stdout(Stdio::null())
-        .status()
-        .context("Failed to invoke ping")?;
-
-    match status.code().expect("ping didn't return a status code?") {
-        0 => Ok(true),
-        1 => Ok(false),
-        n => match status.signal() {
-            Some(sig) => match Signal::try_from(sig) {
-                Ok(sig) => eyre::bail!("ping died from signal {sig}"),
-                Err(_) => eyre::bail!("ping died from unknown signal {sig}"),
-            },
-            None => eyre::bail!("ping exited with status {n}"),
-        },
+    /// Turns the TV on or off and waits for it to happen, retrying according
+    /// to the retry policy.
+    fn turn_on_or_off_wait(&mut self, id: u64, on: bool) -> Outcome {
+        let onoff = if on { "on" } else { "off" };
+        let policy = self.options.retry;
+
+        if on && !self.wait_for_cooldown(id) {
+            return Outcome::Interrupted;
+        }
+        let start = Instant::now();
+
+        for attempt in 1..=policy.max_attempts {
+            log::debug!("[#{id}] Attempt {attempt}/{}", policy.max_attempts);
+
+            let res = if on {
+                turn_on(&mut self.adb, &self.options.wol, true, self.options.pinger)
+            } else {
+                turn_off(&mut self.adb, true, self.options.pinger)
+            };
+            // The TV may still turn off after we've given up waiting for it,
+            // so the cooldown has to start with every attempt to turn it off.
+            if !on && res.is_ok() {
+                self.last_off = Some(Instant::now());
+            }
+            // Turning the TV on or off takes a moment in itself, so the
+            // timeout is only worked out afterwards.
+            let timeout = policy.timeout(start.elapsed());
+            match res.map(|()| self.wait_for(on, timeout)) {
+                Ok(Some(true)) => return Outcome::Done,
+                Ok(Some(false)) => {
+                    log::warn!("[#{id}] TV didn't turn {onoff} within {timeout:.1?}")
+                }
+                Ok(None) => return Outcome::Interrupted,
+                Err(e) => log::error!("[#{id}] Failed to turn TV {onoff}: {e:#}"),
+            }
+
+            if attempt == policy.max_attempts {
+                break;
+            }
+
+            // There's no point in sleeping until the deadline, as the next
+            // attempt would have no time left to wait for the TV.
+            let backoff = policy.backoff(attempt);
+            if backoff >= policy.remaining(start.elapsed()) {
+                log::warn!(
+                    "[#{id}] Not retrying, as turning the TV {onoff} has taken more than {:?}",
+                    policy.deadline
+                );
+                break;
+            }
+
+            log::debug!("[#{id}] Retrying in {backoff:?}");
+            daemon::notify(
+                false,
+                &[NotifyState::Status(format!(
+                    "Retrying TV power-{onoff} in {}s (attempt {}/{})",
+                    backoff.as_secs(),
+                    attempt + 1,
+                    policy.max_attempts
+                ))],
+            )
+            .ok();
+            if !self.sleep(on, backoff) {
+                return Outcome::Interrupted;
+            }
+        }
+
+        Outcome::GaveUp(eyre::eyre!(
+            "Gave up turning the TV {onoff} after {:.0?}",
+            start.elapsed()
+        ))
     }
+
+    /// Waits until the TV can be turned on again after having been turned off.
+    /// Returns false if it should no longer be turned on.
+    fn wait_for_cooldown(&mut self, id: u64) -> bool {
+        let Some(last_off) = self.last_off else {
+            return true;
+        };
+        let ready = last_off + self.options.cooldown;
+
+        let left = ready.saturating_duration_since(Instant::now());
+        if !left.is_zero() {
+            log::info!(
+                "[#{id}] Waiting {}s for the TV to cool down before turning it on",
+                left.as_secs_f32().ceil()
+            );
+        }
+
+        loop {
+            let left = ready.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+
+            let status = format!("Waiting for cooldown ({}s left)", left.as_secs_f32().ceil());
+            daemon::notify(false, &[NotifyState::Status(status)]).ok();
+            // Wake up every second to update the status.
+            if !self.sleep(true, left.min(Duration::from_secs(1))) {
+                return false;
+            }
+        }
+    }
+
+    /// Waits for the TV to turn on or off. Returns whether it did within
+    /// `timeout`, or `None` if it should no longer be `on`.
+    fn wait_for(&mut self, on: bool, timeout: Duration) -> Option<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if !self.sleep(on, POLL_INTERVAL.min(left)) {
+                return None;
+            }
+
+            if self.probe.check(&mut self.adb) == Some(on) {
+                return Some(true);
+            }
+            if Instant::now() >= deadline {
+                return Some(false);
+            }
+
+            log::debug!("TV is not yet {}...", if on { "on" } else { "off" });
+        }
+    }
+}
+
+pub fn send_power_key(adb: &mut adb::Session) -> Result<()> {
+    send_key(adb, KEYCODE_POWER)
 }
 
-fn tv_is_on(ip: IpAddr, output: &str) -> Result<bool> {
-    Ok(ping_tv(ip)? && outputs::is_connected(output)?)
+fn send_key(adb: &mut adb::Session, keycode: i32) -> Result<()> {
+    adb.send_keycode(keycode, Some(Duration::from_secs(1)))
 }
 
 fn find_output(output: Option<String>) -> Result<String> {
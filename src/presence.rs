@@ -1,11 +1,17 @@
 use std::{
     error::Error,
     fmt::{self, Display, Formatter},
+    time::Duration,
 };
 
+use color_eyre::{eyre::Context, Result};
+use dbus::{blocking::LocalConnection, message::SignalArgs};
+
+use generated::{SessionManagerPresence, SessionManagerPresenceStatusChanged};
+
 #[allow(dead_code, clippy::needless_borrow)]
 pub mod generated {
-    include!(concat!(env!("OUT_DIR"), "/generated.rs"));
+    include!(concat!(env!("OUT_DIR"), "/presence.rs"));
 }
 
 // See https://lira.no-ip.org:8443/doc/gnome-session/dbus/gnome-session.html
@@ -19,6 +25,46 @@ pub enum PresenceStatus {
     Idle = 3,
 }
 
+/// Whether the GNOME session is currently active.
+pub fn is_present(dbus: &LocalConnection) -> Result<bool> {
+    let proxy = dbus.with_proxy(
+        "org.gnome.SessionManager",
+        "/org/gnome/SessionManager/Presence",
+        Duration::from_secs(1),
+    );
+
+    let status = proxy
+        .status()
+        .context("Failed to get initial presence status over DBUS")?;
+    let status =
+        PresenceStatus::try_from(status).context("Failed to parse initial presence status")?;
+    log::debug!("Got initial presence status {status:?}");
+
+    Ok(status.is_active())
+}
+
+/// Calls `f` with whether the GNOME session is active whenever its presence
+/// status changes.
+pub fn watch(dbus: &LocalConnection, mut f: impl FnMut(bool) + 'static) -> Result<()> {
+    let match_rule = SessionManagerPresenceStatusChanged::match_rule(None, None);
+    dbus.add_match(
+        match_rule,
+        move |signal: SessionManagerPresenceStatusChanged, _dbus, _msg| {
+            match PresenceStatus::try_from(signal.status) {
+                Ok(status) => {
+                    log::debug!("Got presence status {status:?}");
+                    f(status.is_active());
+                }
+                Err(e) => {
+                    log::error!("Failed to parse presence status: {e}");
+                }
+            }
+            true // Returning true keeps the match active.
+        },
+    )?;
+    Ok(())
+}
+
 impl PresenceStatus {
     pub fn is_active(self) -> bool {
         self != Self::Idle
@@ -0,0 +1,560 @@
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    iter::Peekable,
+    net::{IpAddr, UdpSocket},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+
+use crate::{
+    adb,
+    netlink::{self, NUD_FAILED, NUD_INCOMPLETE, NUD_PERMANENT, NUD_REACHABLE},
+    outputs,
+    ping::Pinger,
+    wakefulness::Wakefulness,
+};
+
+/// A way of checking whether the TV is on.
+pub trait Probe: Debug + Send {
+    /// Returns whether the TV is on, or `None` if this probe can't tell.
+    fn check(&self, adb: &mut adb::Session) -> Option<bool>;
+}
+
+/// The kinds of probes that can be used in a probe expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The TV answers pings.
+    Icmp,
+
+    /// The TV accepts TCP connections on its adb port.
+    Tcp,
+
+    /// The kernel has recently heard from the TV, according to its neighbour
+    /// (ARP) table.
+    Neighbour,
+
+    /// The graphics output that the TV is connected to is connected.
+    Drm,
+
+    /// Android says that it's awake.
+    Wakefulness,
+}
+
+/// A combination of probes, like `icmp and (wakefulness else drm)`.
+///
+/// `a and b` and `a or b` work like you'd expect, where `a or b` is on if
+/// either probe says it's on. If a probe can't tell, the result is only known
+/// if the other probe settles it. `a else b` uses `b` only when `a` can't tell.
+/// `else` binds tighter than `and`, which binds tighter than `or`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Probe(Kind),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Else(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Whether the expression uses a probe of the given kind.
+    pub fn uses(&self, kind: Kind) -> bool {
+        match self {
+            Expr::Probe(k) => *k == kind,
+            Expr::And(a, b) | Expr::Or(a, b) | Expr::Else(a, b) => a.uses(kind) || b.uses(kind),
+        }
+    }
+
+    /// Turns the expression into a probe. `output` has to be given if the
+    /// expression uses the DRM probe.
+    pub fn build(&self, pinger: Pinger, output: Option<&str>) -> Box<dyn Probe> {
+        let build = |e: &Expr| e.build(pinger, output);
+        match self {
+            Expr::Probe(Kind::Icmp) => Box::new(Icmp(pinger)),
+            Expr::Probe(Kind::Tcp) => Box::new(Tcp(pinger)),
+            Expr::Probe(Kind::Neighbour) => Box::new(Neighbour {
+                timeout: pinger.timeout,
+            }),
+            Expr::Probe(Kind::Drm) => Box::new(Drm {
+                output: output
+                    .expect("The output must be known to use the DRM probe")
+                    .to_owned(),
+            }),
+            Expr::Probe(Kind::Wakefulness) => Box::new(Android),
+            Expr::And(a, b) => Box::new(And(build(a), build(b))),
+            Expr::Or(a, b) => Box::new(Or(build(a), build(b))),
+            Expr::Else(a, b) => Box::new(Else(build(a), build(b))),
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "icmp" | "ping" => Ok(Kind::Icmp),
+            "tcp" => Ok(Kind::Tcp),
+            "neighbour" | "neighbor" | "arp" => Ok(Kind::Neighbour),
+            "drm" | "output" => Ok(Kind::Drm),
+            "wakefulness" | "android" => Ok(Kind::Wakefulness),
+            _ => Err(format!(
+                "unknown probe {s:?}, expected icmp, tcp, neighbour, drm or wakefulness"
+            )),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Kind::Icmp => "icmp",
+            Kind::Tcp => "tcp",
+            Kind::Neighbour => "neighbour",
+            Kind::Drm => "drm",
+            Kind::Wakefulness => "wakefulness",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spaced = s.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens = spaced.split_whitespace().peekable();
+
+        let expr = parse_or(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {token:?} in probe expression")),
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Probe(kind) => write!(f, "{kind}"),
+            Expr::And(a, b) => write!(f, "({a} and {b})"),
+            Expr::Or(a, b) => write!(f, "({a} or {b})"),
+            Expr::Else(a, b) => write!(f, "({a} else {b})"),
+        }
+    }
+}
+
+type Tokens<'a> = Peekable<std::str::SplitWhitespace<'a>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_and(tokens)?;
+    while tokens.next_if(|t| t.eq_ignore_ascii_case("or")).is_some() {
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_else(tokens)?;
+    while tokens.next_if(|t| t.eq_ignore_ascii_case("and")).is_some() {
+        expr = Expr::And(Box::new(expr), Box::new(parse_else(tokens)?));
+    }
+    Ok(expr)
+}
+
+fn parse_else(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_probe(tokens)?;
+    while tokens.next_if(|t| t.eq_ignore_ascii_case("else")).is_some() {
+        expr = Expr::Else(Box::new(expr), Box::new(parse_probe(tokens)?));
+    }
+    Ok(expr)
+}
+
+fn parse_probe(tokens: &mut Tokens) -> Result<Expr, String> {
+    match tokens.next() {
+        Some("(") => {
+            let expr = parse_or(tokens)?;
+            match tokens.next() {
+                Some(")") => Ok(expr),
+                _ => Err("missing ) in probe expression".to_owned()),
+            }
+        }
+        Some(token @ ")") => Err(format!("unexpected {token:?} in probe expression")),
+        Some(token)
+            if ["and", "or", "else"]
+                .iter()
+                .any(|k| token.eq_ignore_ascii_case(k)) =>
+        {
+            Err(format!("unexpected {token:?} in probe expression"))
+        }
+        Some(token) => Ok(Expr::Probe(token.parse()?)),
+        None => Err("probe expression ended unexpectedly".to_owned()),
+    }
+}
+
+#[derive(Debug)]
+struct Icmp(Pinger);
+
+impl Probe for Icmp {
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        self.0.ping(adb.addr()).ok()
+    }
+}
+
+#[derive(Debug)]
+struct Tcp(Pinger);
+
+impl Probe for Tcp {
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        Some(self.0.ping_tcp(adb.addr()))
+    }
+}
+
+/// The discard port, which we poke to make the kernel look up the TV's MAC
+/// address.
+const DISCARD_PORT: u16 = 9;
+
+#[derive(Debug)]
+struct Neighbour {
+    timeout: Duration,
+}
+
+impl Probe for Neighbour {
+    /// Checks the TV's entry in the kernel's neighbour table.
+    ///
+    /// The entry is only known to be up to date if the kernel has heard from
+    /// the TV in the last 30 seconds or so. If it's older, a packet is sent to
+    /// the TV to make the kernel look it up again. If that doesn't settle it
+    /// within the timeout, this can't tell.
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        let ip = adb.addr().ip();
+        let deadline = Instant::now() + self.timeout;
+        let mut poked = false;
+
+        loop {
+            let state = match netlink::neighbour_state(ip) {
+                Ok(state) => state,
+                Err(e) => {
+                    log::debug!("Failed to look up {ip} in the neighbour table: {e:#}");
+                    return None;
+                }
+            };
+            log::trace!("Neighbour state of {ip} is {state:?}");
+
+            match state {
+                Some(s) if s & (NUD_REACHABLE | NUD_PERMANENT) != 0 => return Some(true),
+                Some(s) if s & NUD_FAILED != 0 && poked => return Some(false),
+                _ if Instant::now() >= deadline => {
+                    return match state {
+                        None => Some(false),
+                        Some(s) if s & (NUD_INCOMPLETE | NUD_FAILED) != 0 => Some(false),
+                        Some(_) => None,
+                    };
+                }
+                _ => {}
+            }
+
+            if !poked {
+                poked = true;
+                if let Err(e) = UdpSocket::bind((unspecified(ip), 0))
+                    .and_then(|s| s.send_to(&[], (ip, DISCARD_PORT)))
+                {
+                    log::debug!("Failed to send packet to {ip}: {e}");
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => IpAddr::from([0; 4]),
+        IpAddr::V6(_) => IpAddr::from([0u16; 8]),
+    }
+}
+
+#[derive(Debug)]
+struct Drm {
+    output: String,
+}
+
+impl Probe for Drm {
+    fn check(&self, _adb: &mut adb::Session) -> Option<bool> {
+        match outputs::is_connected(&self.output) {
+            Ok(connected) => Some(connected),
+            Err(e) => {
+                log::debug!("Failed to check output {}: {e:#}", self.output);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Android;
+
+impl Probe for Android {
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        match Wakefulness::query(adb) {
+            Wakefulness::Unreachable => None,
+            wakefulness => Some(wakefulness.is_awake()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct And(Box<dyn Probe>, Box<dyn Probe>);
+
+impl Probe for And {
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        match self.0.check(adb) {
+            Some(false) => Some(false),
+            a => match (a, self.1.check(adb)) {
+                (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Or(Box<dyn Probe>, Box<dyn Probe>);
+
+impl Probe for Or {
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        match self.0.check(adb) {
+            Some(true) => Some(true),
+            a => match (a, self.1.check(adb)) {
+                (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Else(Box<dyn Probe>, Box<dyn Probe>);
+
+impl Probe for Else {
+    fn check(&self, adb: &mut adb::Session) -> Option<bool> {
+        self.0.check(adb).or_else(|| self.1.check(adb))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    fn parse(s: &str) -> Result<Expr, String> {
+        s.parse()
+    }
+
+    fn probe(kind: Kind) -> Box<Expr> {
+        Box::new(Expr::Probe(kind))
+    }
+
+    #[test]
+    fn single_probes() {
+        for (s, kind) in [
+            ("icmp", Kind::Icmp),
+            ("ping", Kind::Icmp),
+            ("tcp", Kind::Tcp),
+            ("neighbour", Kind::Neighbour),
+            ("neighbor", Kind::Neighbour),
+            ("arp", Kind::Neighbour),
+            ("drm", Kind::Drm),
+            ("output", Kind::Drm),
+            ("wakefulness", Kind::Wakefulness),
+            ("Android", Kind::Wakefulness),
+            ("  ICMP  ", Kind::Icmp),
+            ("((tcp))", Kind::Tcp),
+        ] {
+            assert_eq!(parse(s), Ok(Expr::Probe(kind)), "{s}");
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        use Kind::*;
+
+        for (s, expected) in [
+            (
+                "icmp and wakefulness else drm",
+                Expr::And(
+                    probe(Icmp),
+                    Box::new(Expr::Else(probe(Wakefulness), probe(Drm))),
+                ),
+            ),
+            (
+                "icmp or tcp and drm",
+                Expr::Or(probe(Icmp), Box::new(Expr::And(probe(Tcp), probe(Drm)))),
+            ),
+            (
+                "icmp else tcp or drm",
+                Expr::Or(Box::new(Expr::Else(probe(Icmp), probe(Tcp))), probe(Drm)),
+            ),
+            (
+                "(icmp or tcp) and drm",
+                Expr::And(Box::new(Expr::Or(probe(Icmp), probe(Tcp))), probe(Drm)),
+            ),
+            ("(icmp)AND(tcp)", Expr::And(probe(Icmp), probe(Tcp))),
+            // Operators of the same precedence are left-associative.
+            (
+                "icmp or tcp OR drm",
+                Expr::Or(Box::new(Expr::Or(probe(Icmp), probe(Tcp))), probe(Drm)),
+            ),
+        ] {
+            assert_eq!(parse(s), Ok(expected), "{s}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "icmp and wakefulness else drm",
+            "icmp or (tcp and arp) else drm",
+            "(icmp else tcp) and (drm or wakefulness)",
+        ] {
+            let expr = parse(s).unwrap();
+            assert_eq!(parse(&expr.to_string()), Ok(expr), "{s}");
+        }
+        assert_eq!(
+            parse("ping and android else output").unwrap().to_string(),
+            "(icmp and (wakefulness else drm))"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for (s, error) in [
+            ("", "probe expression ended unexpectedly"),
+            ("icmp and", "probe expression ended unexpectedly"),
+            ("(", "probe expression ended unexpectedly"),
+            ("(icmp", "missing ) in probe expression"),
+            ("(icmp tcp)", "missing ) in probe expression"),
+            ("icmp)", "unexpected \")\" in probe expression"),
+            ("()", "unexpected \")\" in probe expression"),
+            ("icmp tcp", "unexpected \"tcp\" in probe expression"),
+            ("and icmp", "unexpected \"and\" in probe expression"),
+            (
+                "icmp or else tcp",
+                "unexpected \"else\" in probe expression",
+            ),
+            (
+                "icmp and snmp",
+                "unknown probe \"snmp\", expected icmp, tcp, neighbour, drm or wakefulness",
+            ),
+        ] {
+            assert_eq!(parse(s), Err(error.to_owned()), "{s}");
+        }
+    }
+
+    #[test]
+    fn uses() {
+        let expr = parse("icmp and (wakefulness else drm)").unwrap();
+        assert!(expr.uses(Kind::Drm));
+        assert!(expr.uses(Kind::Icmp));
+        assert!(!expr.uses(Kind::Tcp));
+    }
+
+    /// A probe with a fixed answer, which counts how often it's asked.
+    #[derive(Debug, Clone)]
+    struct Fixed(Option<bool>, Arc<AtomicUsize>);
+
+    impl Probe for Fixed {
+        fn check(&self, _adb: &mut adb::Session) -> Option<bool> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0
+        }
+    }
+
+    fn fixed(answer: Option<bool>) -> (Box<dyn Probe>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (Box::new(Fixed(answer, Arc::clone(&calls))), calls)
+    }
+
+    type Combinator = fn(Box<dyn Probe>, Box<dyn Probe>) -> Box<dyn Probe>;
+
+    /// The answers of `a` and `b`, what combining them should give, and
+    /// whether `b` has to be asked.
+    type Row = (Option<bool>, Option<bool>, Option<bool>, bool);
+
+    /// Checks `combine(a, b)` for every combination of answers.
+    fn truth_table(combine: Combinator, table: [Row; 9]) {
+        let mut adb = adb::Session::with_test_key("127.0.0.1:5555".parse().unwrap());
+        for (a, b, expected, asks_b) in table {
+            let (probe_a, _) = fixed(a);
+            let (probe_b, calls_b) = fixed(b);
+            let probe = combine(probe_a, probe_b);
+            assert_eq!(probe.check(&mut adb), expected, "{a:?}, {b:?}");
+            assert_eq!(calls_b.load(Ordering::Relaxed) > 0, asks_b, "{a:?}, {b:?}");
+        }
+    }
+
+    const T: Option<bool> = Some(true);
+    const F: Option<bool> = Some(false);
+    const N: Option<bool> = None;
+
+    #[test]
+    fn and() {
+        truth_table(
+            |a, b| Box::new(And(a, b)),
+            [
+                (T, T, T, true),
+                (T, F, F, true),
+                (T, N, N, true),
+                (F, T, F, false),
+                (F, F, F, false),
+                (F, N, F, false),
+                (N, T, N, true),
+                (N, F, F, true),
+                (N, N, N, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn or() {
+        truth_table(
+            |a, b| Box::new(Or(a, b)),
+            [
+                (T, T, T, false),
+                (T, F, T, false),
+                (T, N, T, false),
+                (F, T, T, true),
+                (F, F, F, true),
+                (F, N, N, true),
+                (N, T, T, true),
+                (N, F, N, true),
+                (N, N, N, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn else_() {
+        truth_table(
+            |a, b| Box::new(Else(a, b)),
+            [
+                (T, T, T, false),
+                (T, F, T, false),
+                (T, N, T, false),
+                (F, T, F, false),
+                (F, F, F, false),
+                (F, N, F, false),
+                (N, T, T, true),
+                (N, F, F, true),
+                (N, N, N, true),
+            ],
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How the service retries turning the TV on or off.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// How many times to try before giving up.
+    pub max_attempts: u32,
+
+    /// How long to wait for the TV to turn on or off after each attempt.
+    pub attempt_timeout: Duration,
+
+    /// How long to wait before the first retry. This doubles for each retry,
+    /// up to `max_backoff`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+
+    /// How long to keep trying in total.
+    pub deadline: Duration,
+}
+
+impl Policy {
+    /// How long to wait after the given attempt, which starts at 1.
+    ///
+    /// The delay is picked randomly between half and all of the exponential
+    /// backoff, so that retries don't end up in lockstep with whatever is
+    /// making the TV fail.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self.backoff.saturating_mul(factor).min(self.max_backoff);
+        rand::thread_rng().gen_range(backoff / 2..=backoff)
+    }
+
+    /// How much of the deadline is left after trying for `elapsed`.
+    pub fn remaining(&self, elapsed: Duration) -> Duration {
+        self.deadline.saturating_sub(elapsed)
+    }
+
+    /// How long to wait for the TV to turn on or off after an attempt, after
+    /// trying for `elapsed`. This never goes past the deadline.
+    pub fn timeout(&self, elapsed: Duration) -> Duration {
+        self.attempt_timeout.min(self.remaining(elapsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: Policy = Policy {
+        max_attempts: 10,
+        attempt_timeout: Duration::from_secs(10),
+        backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(30),
+        deadline: Duration::from_secs(120),
+    };
+
+    #[test]
+    fn backoff() {
+        for (attempt, max) in [(1, 1), (2, 2), (3, 4), (5, 16), (6, 30), (100, 30)] {
+            let max = Duration::from_secs(max);
+            for _ in 0..20 {
+                let backoff = POLICY.backoff(attempt);
+                assert!(
+                    (max / 2..=max).contains(&backoff),
+                    "attempt {attempt}: {backoff:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let secs = Duration::from_secs;
+        assert_eq!(POLICY.timeout(secs(0)), secs(10));
+        assert_eq!(POLICY.timeout(secs(110)), secs(10));
+        assert_eq!(POLICY.timeout(secs(115)), secs(5));
+        assert_eq!(POLICY.timeout(secs(120)), secs(0));
+        assert_eq!(POLICY.timeout(secs(500)), secs(0));
+    }
+}
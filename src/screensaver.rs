@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{self, Context},
+    Help, Result,
+};
+use dbus::{arg::ReadAll, blocking::LocalConnection, message::SignalArgs};
+
+use crate::idle;
+
+#[allow(dead_code, clippy::needless_borrow, clippy::bind_instead_of_map)]
+pub mod gnome {
+    include!(concat!(env!("OUT_DIR"), "/gnome_screensaver.rs"));
+}
+
+#[allow(dead_code, clippy::needless_borrow, clippy::bind_instead_of_map)]
+pub mod freedesktop {
+    include!(concat!(env!("OUT_DIR"), "/freedesktop_screensaver.rs"));
+}
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The screensaver interfaces that desktops provide. GNOME has its own, while
+/// KDE Plasma, Xfce and others use the freedesktop one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    Gnome,
+    Freedesktop,
+}
+
+impl Interface {
+    fn name(self) -> &'static str {
+        match self {
+            Interface::Gnome => "org.gnome.ScreenSaver",
+            Interface::Freedesktop => "org.freedesktop.ScreenSaver",
+        }
+    }
+
+    fn path(self) -> &'static str {
+        match self {
+            Interface::Gnome => "/org/gnome/ScreenSaver",
+            Interface::Freedesktop => "/org/freedesktop/ScreenSaver",
+        }
+    }
+
+    /// Finds out which screensaver is running on the session bus.
+    ///
+    /// GNOME is checked first, as it may also provide parts of the freedesktop
+    /// interface for other programs to inhibit the screensaver with.
+    pub fn detect(dbus: &LocalConnection) -> Result<Self> {
+        for interface in [Interface::Gnome, Interface::Freedesktop] {
+            if idle::has_owner(dbus, interface.name())? {
+                log::info!("Using screensaver {}", interface.name());
+                return Ok(interface);
+            }
+        }
+
+        Err(eyre::eyre!(
+            "Found neither org.gnome.ScreenSaver nor org.freedesktop.ScreenSaver on the session bus"
+        ))
+        .suggestion("Make sure that your desktop's screensaver or screen locker is running")
+    }
+
+    /// Whether the screensaver is inactive, meaning that the user is there.
+    pub fn is_present(self, dbus: &LocalConnection) -> Result<bool> {
+        let proxy = dbus.with_proxy(self.name(), self.path(), TIMEOUT);
+
+        let active = match self {
+            Interface::Gnome => gnome::ScreenSaver::get_active(&proxy),
+            Interface::Freedesktop => freedesktop::ScreenSaver::get_active(&proxy),
+        }
+        .context("Failed to get initial screensaver state over DBUS")?;
+        log::debug!("Screensaver is initially {}", describe(active));
+
+        Ok(!active)
+    }
+
+    /// Calls `f` with whether the screensaver is inactive whenever it turns on
+    /// or off.
+    pub fn watch(self, dbus: &LocalConnection, f: impl FnMut(bool) + 'static) -> Result<()> {
+        match self {
+            Interface::Gnome => {
+                watch_signal(dbus, |s: &gnome::ScreenSaverActiveChanged| s.new_value, f)
+            }
+            Interface::Freedesktop => watch_signal(
+                dbus,
+                |s: &freedesktop::ScreenSaverActiveChanged| s.new_value,
+                f,
+            ),
+        }
+    }
+}
+
+fn watch_signal<S: SignalArgs + ReadAll + 'static>(
+    dbus: &LocalConnection,
+    active: fn(&S) -> bool,
+    mut f: impl FnMut(bool) + 'static,
+) -> Result<()> {
+    dbus.add_match(S::match_rule(None, None), move |signal: S, _dbus, _msg| {
+        let active = active(&signal);
+        log::debug!("Screensaver became {}", describe(active));
+        f(!active);
+        true // Returning true keeps the match active.
+    })?;
+    Ok(())
+}
+
+fn describe(active: bool) -> &'static str {
+    if active {
+        "active"
+    } else {
+        "inactive"
+    }
+}
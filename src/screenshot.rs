@@ -0,0 +1,47 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+
+use crate::adb;
+
+/// Large screens make for large PNGs, which take a while to encode and send
+/// over WiFi.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Takes a screenshot of the TV and saves it as a PNG in `file`, or writes it
+/// to stdout if `file` is `-`.
+pub fn save(adb: &mut adb::Session, file: &Path) -> Result<()> {
+    let mut png = Vec::new();
+    adb.exec("screencap -p", &mut png, Some(TIMEOUT))
+        .context("Failed to take screenshot")?;
+
+    // screencap prints errors to the same stream as the image, so anything
+    // that isn't a PNG is an error message.
+    if !png.starts_with(PNG_MAGIC) {
+        let message = String::from_utf8_lossy(&png);
+        match message.trim() {
+            "" => eyre::bail!("Failed to take screenshot: screencap didn't output anything"),
+            message => eyre::bail!("Failed to take screenshot: {message}"),
+        }
+    }
+
+    if file == Path::new("-") {
+        io::stdout()
+            .write_all(&png)
+            .context("Failed to write screenshot to stdout")
+    } else {
+        fs::write(file, &png).with_context(|| format!("Failed to write screenshot to {file:?}"))?;
+        log::info!("Saved screenshot to {file:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    net::SocketAddr,
+};
+
+use color_eyre::{
+    eyre::{self, Context},
+    owo_colors::{OwoColorize, Stream},
+    Result,
+};
+use owo_colors::colored::Color;
+use serde::Serialize;
+
+use crate::{adb, outputs, ping::Pinger, wakefulness::Wakefulness};
+
+/// Whether the TV is on, as far as we can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Power {
+    On,
+    Off,
+    Unknown,
+}
+
+impl Power {
+    /// The exit code of the status command, so that scripts can tell the
+    /// states apart.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Power::On => 0,
+            Power::Off => 3,
+            Power::Unknown => 4,
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Power::On => Color::Green,
+            Power::Off => Color::Red,
+            Power::Unknown => Color::Yellow,
+        }
+    }
+}
+
+impl Display for Power {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Power::On => "on",
+            Power::Off => "off",
+            Power::Unknown => "unknown",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub power: Power,
+    pub ping: bool,
+    pub adb: bool,
+
+    /// Android's idea of the power state, if we could ask it.
+    pub wakefulness: Option<Wakefulness>,
+    pub output: Option<OutputStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputStatus {
+    pub name: String,
+    pub status: String,
+    pub connected: bool,
+}
+
+impl Status {
+    /// Checks everything we know how to check about the TV.
+    pub fn probe(addr: SocketAddr, output: Option<String>, pinger: Pinger) -> Result<Self> {
+        let ping = pinger.ping(addr)?;
+
+        let mut session = adb::Session::new(addr)?;
+        let wakefulness = match Wakefulness::query(&mut session) {
+            Wakefulness::Unreachable => None,
+            wakefulness => Some(wakefulness),
+        };
+
+        let output = find_output(output)?.map(|o| OutputStatus {
+            connected: o.status == outputs::Status::Connected,
+            status: o.status.to_string(),
+            name: o.name,
+        });
+
+        let power = match (wakefulness, &output) {
+            (Some(wakefulness), _) => on_or_off(wakefulness.is_awake()),
+            _ if !ping => Power::Off,
+            (None, Some(output)) => on_or_off(output.connected),
+            (None, None) => Power::Unknown,
+        };
+
+        Ok(Self {
+            power,
+            ping,
+            adb: session.is_connected(),
+            wakefulness,
+            output,
+        })
+    }
+
+    pub fn print(&self) {
+        let yes_no = |b: bool, yes: &str, no: &str| {
+            if b {
+                (yes.to_owned(), Color::Green)
+            } else {
+                (no.to_owned(), Color::Red)
+            }
+        };
+
+        print_line("power", self.power.to_string(), self.power.color());
+
+        let (ping, color) = yes_no(self.ping, "reachable", "unreachable");
+        print_line("ping", ping, color);
+
+        let (adb, color) = yes_no(self.adb, "connected", "disconnected");
+        print_line("adb", adb, color);
+
+        match self.wakefulness {
+            Some(w) => {
+                let color = if w.is_awake() {
+                    Color::Green
+                } else {
+                    Color::Red
+                };
+                print_line("wakefulness", w.to_string(), color);
+            }
+            None => print_line("wakefulness", "unknown".to_owned(), Color::Yellow),
+        }
+
+        match &self.output {
+            Some(o) => {
+                let color = if o.connected {
+                    Color::Green
+                } else {
+                    Color::Red
+                };
+                print_line("output", format!("{} {}", o.name, o.status), color);
+            }
+            None => print_line("output", "unknown".to_owned(), Color::Yellow),
+        }
+    }
+}
+
+/// Prints the TV's status and returns the exit code the program should exit
+/// with.
+pub fn show(addr: SocketAddr, output: Option<String>, pinger: Pinger, json: bool) -> Result<i32> {
+    let status = Status::probe(addr, output, pinger)?;
+
+    if json {
+        let json = serde_json::to_string_pretty(&status).context("Failed to serialize status")?;
+        println!("{json}");
+    } else {
+        status.print();
+    }
+
+    Ok(status.power.exit_code())
+}
+
+fn on_or_off(on: bool) -> Power {
+    if on {
+        Power::On
+    } else {
+        Power::Off
+    }
+}
+
+fn print_line(key: &str, value: String, color: Color) {
+    let value = value.if_supports_color(Stream::Stdout, |s| s.color(color));
+    println!("{key} {value}");
+}
+
+/// Finds the output the TV is connected to. Unlike the service, this doesn't
+/// fail if we can't tell which output to use, as the TV might just be off.
+fn find_output(output: Option<String>) -> Result<Option<outputs::Output>> {
+    let all = outputs::all()?;
+
+    if let Some(name) = output {
+        return match all.into_iter().find(|o| o.name == name) {
+            Some(output) => Ok(Some(output)),
+            None => eyre::bail!("Output {name} doesn't exist"),
+        };
+    }
+
+    let mut connected = all
+        .into_iter()
+        .filter(|o| o.status == outputs::Status::Connected);
+    match (connected.next(), connected.next()) {
+        (Some(output), None) => Ok(Some(output)),
+        _ => Ok(None),
+    }
+}
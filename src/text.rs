@@ -0,0 +1,263 @@
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use color_eyre::{
+    eyre::{self, Context},
+    Help, Result,
+};
+
+use crate::adb;
+
+/// Android key code.
+const KEYCODE_ENTER: i32 = 66;
+
+/// How many characters to type with each `input text`. Some TVs drop
+/// characters when given long strings.
+const CHUNK_LEN: usize = 100;
+
+/// The input method of the [ADB keyboard](https://github.com/senzhk/ADBKeyBoard)
+/// app, which can type characters that `input text` can't.
+const ADB_KEYBOARD_IME: &str = "com.android.adbkeyboard/.AdbIME";
+
+/// The broadcast that makes the ADB keyboard type base64-encoded text.
+const ADB_KEYBOARD_ACTION: &str = "ADB_INPUT_B64";
+
+/// Each line of text can take a while to type, as `input` is rather slow to
+/// start.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Types `text` on the TV, or text read from stdin if `text` is `-`.
+pub fn type_arg(adb: &mut adb::Session, text: &str) -> Result<()> {
+    if text != "-" {
+        return send(adb, text);
+    }
+
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .context("Failed to read text from stdin")?;
+    let text = text
+        .strip_suffix('\n')
+        .map(|t| t.strip_suffix('\r').unwrap_or(t))
+        .unwrap_or(&text);
+    send(adb, text)
+}
+
+/// Types `text` on the TV. Newlines are typed by pressing enter.
+///
+/// `input text` can only type ASCII, so anything else is typed with the ADB
+/// keyboard, which has to be installed and selected as the TV's keyboard.
+pub fn send(adb: &mut adb::Session, text: &str) -> Result<()> {
+    if text.is_empty() {
+        return Ok(());
+    }
+
+    if text.chars().all(|c| c == '\n' || (' '..='~').contains(&c)) {
+        log::debug!("Typing {text:?} with input text");
+        adb.shell(&to_shell_command(text), Some(TIMEOUT))
+    } else {
+        log::debug!("Typing {text:?} with the ADB keyboard");
+        send_with_adb_keyboard(adb, text)
+    }
+}
+
+/// The shell command that types `text` with `input text`. `text` must only
+/// contain printable ASCII characters and newlines.
+pub fn to_shell_command(text: &str) -> String {
+    let mut commands = Vec::new();
+
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            commands.push(format!("input keyevent {KEYCODE_ENTER}"));
+        }
+        commands.extend(
+            chunks(line)
+                .into_iter()
+                .map(|chunk| format!("input text {}", escape(&chunk))),
+        );
+    }
+
+    commands.join(" && ")
+}
+
+/// Splits `line` into chunks of at most [`CHUNK_LEN`] characters.
+///
+/// `input text` turns `%s` into a space, and there's no way of escaping it.
+/// Chunks are therefore split in between any `%` and `s` so that they're typed
+/// as-is.
+fn chunks(line: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        chunk.push(c);
+        if chunk.len() >= CHUNK_LEN || (c == '%' && chars.peek() == Some(&'s')) {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+/// Splits `text` into chunks of at most [`CHUNK_LEN`] characters for the ADB
+/// keyboard. Unlike `input text`, it doesn't treat anything specially.
+fn char_chunks(text: &str) -> Vec<String> {
+    let chars = text.chars().collect::<Vec<_>>();
+    chars.chunks(CHUNK_LEN).map(String::from_iter).collect()
+}
+
+/// Escapes a chunk of text for `input text`, which is run by the TV's shell.
+fn escape(chunk: &str) -> String {
+    adb::quote(&chunk.replace(' ', "%s"))
+}
+
+fn send_with_adb_keyboard(adb: &mut adb::Session, text: &str) -> Result<()> {
+    let ime = adb
+        .capture("settings get secure default_input_method", Some(TIMEOUT))?
+        .stdout;
+    if ime.trim() != ADB_KEYBOARD_IME {
+        return Err(eyre::eyre!(
+            "Can't type non-ASCII text without the ADB keyboard"
+        ))
+        .suggestion("Install ADBKeyBoard on the TV and select it as the keyboard in the settings");
+    }
+
+    for chunk in char_chunks(text) {
+        let command = format!(
+            "am broadcast -a {ADB_KEYBOARD_ACTION} --es msg {}",
+            BASE64.encode(chunk)
+        );
+        adb.capture(&command, Some(TIMEOUT))?
+            .check()
+            .context("Failed to type text with the ADB keyboard")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    /// Runs `command` with a fake `input` that types like the TV's does,
+    /// and returns what it typed.
+    fn typed(command: &str) -> String {
+        let fake_input = r#"
+            input() {
+                case "$1" in
+                    text) printf '%s' "$2" | sed 's/%s/ /g' ;;
+                    keyevent) [ "$2" = 66 ] && echo ;;
+                esac
+            }
+        "#;
+        let output = process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{fake_input}\n{command}"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn spaces() {
+        assert_eq!(
+            to_shell_command("hello big world"),
+            "input text 'hello%sbig%sworld'"
+        );
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            to_shell_command(r#"it's "quoted""#),
+            r#"input text 'it'\''s%s"quoted"'"#
+        );
+    }
+
+    #[test]
+    fn shell_metacharacters() {
+        let text = r"a&b;c|d<e>f(g)h$i`j\k*l~m#n";
+        assert_eq!(to_shell_command(text), format!("input text '{text}'"));
+    }
+
+    #[test]
+    fn newlines() {
+        assert_eq!(
+            to_shell_command("a\n\nb"),
+            "input text 'a' && input keyevent 66 && input keyevent 66 && input text 'b'"
+        );
+        assert_eq!(to_shell_command("\n"), "input keyevent 66");
+    }
+
+    #[test]
+    fn percent_s() {
+        assert_eq!(
+            to_shell_command("100%sure"),
+            "input text '100%' && input text 'sure'"
+        );
+        // A space after a percent sign doesn't need splitting, as `%%s` is
+        // typed as `% `.
+        assert_eq!(to_shell_command("5% off"), "input text '5%%soff'");
+    }
+
+    #[test]
+    fn chunk_lengths() {
+        let line = "a".repeat(2 * CHUNK_LEN + 50);
+        let lens: Vec<_> = chunks(&line).iter().map(String::len).collect();
+        assert_eq!(lens, [CHUNK_LEN, CHUNK_LEN, 50]);
+        assert!(chunks("").is_empty());
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        // A space at the end of a chunk is escaped within that chunk.
+        let line = format!("{} b", "a".repeat(CHUNK_LEN - 1));
+        let split = chunks(&line);
+        assert_eq!(split.len(), 2);
+        assert!(escape(&split[0]).ends_with("a%s'"));
+        assert_eq!(split[1], "b");
+
+        // A chunk ending in a percent sign never has an s after it.
+        let line = format!("{}%s", "a".repeat(CHUNK_LEN - 1));
+        for chunk in chunks(&line) {
+            assert!(!chunk.contains("%s"), "{chunk:?}");
+        }
+    }
+
+    #[test]
+    fn char_chunk_boundaries() {
+        let text = "ü€😀".repeat(CHUNK_LEN);
+        let chunks = char_chunks(&text);
+        assert_eq!(chunks.len(), 3);
+        for chunk in &chunks {
+            assert_eq!(chunk.chars().count(), CHUNK_LEN);
+        }
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in [
+            "hello world",
+            r#"it's "quoted""#,
+            r"a&b;c|d<e>f(g)h$i`j\k",
+            "100%sure and 5% off %",
+            "two\nlines",
+            "  leading and trailing  ",
+            &format!("{}%s {}", "x".repeat(CHUNK_LEN - 1), "y ".repeat(CHUNK_LEN)),
+        ] {
+            let command = to_shell_command(text);
+            assert_eq!(typed(&command), text, "{command}");
+        }
+    }
+}
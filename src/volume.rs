@@ -0,0 +1,203 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{self, Context},
+    Result,
+};
+
+use crate::adb;
+
+/// Android's music stream, which is what the volume buttons on the remote
+/// control.
+const STREAM_MUSIC: u32 = 3;
+
+/// Android key code.
+const KEYCODE_VOLUME_MUTE: i32 = 164;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The volume of the TV's music stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    pub level: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Volume {
+    /// Asks the TV for its current volume.
+    pub fn get(adb: &mut adb::Session) -> Result<Self> {
+        let output = media_volume(adb, "--get").context("Failed to get volume")?;
+        Self::parse(&output).with_context(|| format!("Failed to parse volume from {output:?}"))
+    }
+
+    /// Parses the `volume is 7 in range [0..15]` line printed by
+    /// `media volume --get`.
+    fn parse(output: &str) -> Result<Self> {
+        let line = output
+            .lines()
+            .find_map(|line| line.split_once("volume is ").map(|(_, rest)| rest))
+            .ok_or_else(|| eyre::eyre!("No volume in output"))?;
+
+        let (level, range) = line
+            .split_once(" in range ")
+            .ok_or_else(|| eyre::eyre!("No volume range in output"))?;
+        let (min, max) = range
+            .trim()
+            .strip_prefix('[')
+            .and_then(|r| r.strip_suffix(']'))
+            .and_then(|r| r.split_once(".."))
+            .ok_or_else(|| eyre::eyre!("Invalid volume range {range:?}"))?;
+
+        let parse = |n: &str| {
+            n.trim()
+                .parse()
+                .with_context(|| format!("Invalid volume {n:?}"))
+        };
+        Ok(Self {
+            level: parse(level)?,
+            min: parse(min)?,
+            max: parse(max)?,
+        })
+    }
+}
+
+impl Display for Volume {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (range {}-{})", self.level, self.min, self.max)
+    }
+}
+
+/// Sets the TV's volume to `level`, which has to be within the range that the
+/// TV reports.
+pub fn set(adb: &mut adb::Session, level: u32) -> Result<()> {
+    let current = Volume::get(adb)?;
+    eyre::ensure!(
+        (current.min..=current.max).contains(&level),
+        "Volume {level} is out of range. The TV's volume goes from {} to {}",
+        current.min,
+        current.max
+    );
+
+    if current.level == level {
+        log::debug!("Volume is already {level}");
+        return Ok(());
+    }
+
+    log::info!("Changing volume from {} to {level}", current.level);
+    media_volume(adb, &format!("--set {level}")).context("Failed to set volume")?;
+    Ok(())
+}
+
+/// Turns the volume up one step.
+pub fn up(adb: &mut adb::Session) -> Result<()> {
+    media_volume(adb, "--adj raise").context("Failed to turn the volume up")?;
+    Ok(())
+}
+
+/// Turns the volume down one step.
+pub fn down(adb: &mut adb::Session) -> Result<()> {
+    media_volume(adb, "--adj lower").context("Failed to turn the volume down")?;
+    Ok(())
+}
+
+/// Toggles mute, like the mute button on the remote.
+pub fn mute(adb: &mut adb::Session) -> Result<()> {
+    adb.send_keycode(KEYCODE_VOLUME_MUTE, Some(TIMEOUT))
+        .context("Failed to toggle mute")
+}
+
+/// Prints the TV's volume.
+pub fn print(adb: &mut adb::Session) -> Result<()> {
+    let volume = Volume::get(adb)?;
+    log::debug!("Volume is {volume}");
+    println!("{}", volume.level);
+    Ok(())
+}
+
+/// Runs `media volume` for the music stream with the given arguments and
+/// returns its output.
+///
+/// Newer versions of Android have moved this to `cmd media_session volume`,
+/// and older ones don't have `cmd`, so this tries both.
+fn media_volume(adb: &mut adb::Session, args: &str) -> Result<String> {
+    let command = format!(
+        "cmd media_session volume --stream {STREAM_MUSIC} {args} 2>/dev/null \
+         || media volume --stream {STREAM_MUSIC} {args}"
+    );
+    let output = adb.capture(&command, Some(TIMEOUT))?;
+    output.check()?;
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cmd_media_session() {
+        let output = "\
+[V] will get volume
+[V] will control stream=3 (STREAM_MUSIC)
+[V] Connecting to AudioService
+[V] volume is 7 in range [0..15]
+";
+        assert_eq!(
+            Volume::parse(output).unwrap(),
+            Volume {
+                level: 7,
+                min: 0,
+                max: 15
+            }
+        );
+    }
+
+    #[test]
+    fn parse_media() {
+        let output = "\
+[V] will get volume
+[V] will control stream=3 (STREAM_MUSIC)
+[V] Connecting to AudioService
+[V] volume is 42 in range [0..100]
+";
+        assert_eq!(
+            Volume::parse(output).unwrap(),
+            Volume {
+                level: 42,
+                min: 0,
+                max: 100
+            }
+        );
+    }
+
+    #[test]
+    fn parse_bare_line() {
+        assert_eq!(
+            Volume::parse("volume is 3 in range [1..30]").unwrap(),
+            Volume {
+                level: 3,
+                min: 1,
+                max: 30
+            }
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for output in [
+            "",
+            "/system/bin/sh: media: inaccessible or not found",
+            "[V] will get volume\n[V] Connecting to AudioService\n",
+            "volume is 7",
+            "volume is 7 in range 0..15",
+            "volume is 7 in range [0-15]",
+            "volume is loud in range [0..15]",
+            "volume is -1 in range [0..15]",
+        ] {
+            assert!(Volume::parse(output).is_err(), "{output}");
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::adb;
+
+/// How long to wait for dumpsys to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Only pulls out the lines we care about, as the full dumpsys output is
+/// rather large.
+const QUERY: &str = "dumpsys power | grep mWakefulness=; dumpsys display | grep mScreenState=";
+
+/// The TV's power state as Android sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Wakefulness {
+    Awake,
+    Asleep,
+    Dozing,
+
+    /// We couldn't ask the TV, or it gave us an answer we didn't understand.
+    Unreachable,
+}
+
+impl Wakefulness {
+    /// Asks the TV for its power state over adb.
+    pub fn query(adb: &mut adb::Session) -> Self {
+        let output = match adb.capture(QUERY, Some(QUERY_TIMEOUT)) {
+            Ok(output) => output,
+            Err(e) => {
+                log::debug!("Failed to query TV power state: {e:#}");
+                return Self::Unreachable;
+            }
+        };
+
+        match Self::parse(&output.stdout) {
+            Some(wakefulness) => {
+                log::debug!("TV power state is {wakefulness}");
+                wakefulness
+            }
+            None => {
+                log::warn!("Failed to parse TV power state from {:?}", output.stdout);
+                Self::Unreachable
+            }
+        }
+    }
+
+    pub fn is_awake(self) -> bool {
+        self == Self::Awake
+    }
+
+    /// Parses `mWakefulness` from `dumpsys power`, falling back to
+    /// `mScreenState` from `dumpsys display`.
+    fn parse(dumpsys: &str) -> Option<Self> {
+        let value = |key: &str| {
+            dumpsys
+                .lines()
+                .find_map(|line| line.trim().strip_prefix(key))
+                .map(str::trim)
+        };
+
+        let wakefulness = value("mWakefulness=").and_then(|w| match w {
+            // Dreaming means that a screensaver is running, so the screen is
+            // still on.
+            "Awake" | "Dreaming" => Some(Self::Awake),
+            "Asleep" => Some(Self::Asleep),
+            "Dozing" => Some(Self::Dozing),
+            _ => None,
+        });
+
+        wakefulness.or_else(|| {
+            value("mScreenState=").and_then(|s| match s {
+                "ON" | "VR" => Some(Self::Awake),
+                "OFF" => Some(Self::Asleep),
+                "DOZE" | "DOZE_SUSPEND" | "ON_SUSPEND" => Some(Self::Dozing),
+                _ => None,
+            })
+        })
+    }
+}
+
+impl Display for Wakefulness {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Wakefulness::Awake => "awake",
+            Wakefulness::Asleep => "asleep",
+            Wakefulness::Dozing => "dozing",
+            Wakefulness::Unreachable => "unreachable",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An excerpt of `dumpsys power` from an Android 9 TV.
+    const POWER_AWAKE: &str = "\
+POWER MANAGER (dumpsys power)
+
+Power Manager State:
+  Settings power_manager_constants:
+    no_cached_wake_locks=true
+  mDirty=0x0
+  mWakefulness=Awake
+  mWakefulnessChanging=false
+  mIsPowered=true
+  mPlugType=1
+  mBatteryLevel=0
+";
+
+    /// An excerpt of `dumpsys power` from an Android 12 TV that's going to
+    /// sleep.
+    const POWER_ASLEEP: &str = "\
+Power Manager State:
+  mDirty=0x20
+  mWakefulnessChanging=true
+  mWakefulness=Asleep
+  mWakefulnessRaw=Asleep
+  mIsPowered=true
+";
+
+    /// An excerpt of `dumpsys display`.
+    const DISPLAY: &str = "\
+Display Power Controller Locked State:
+  mDisplayReadyLocked=true
+  mPendingRequestChangedLocked=false
+
+Display Power State:
+  mScreenState=%
+  mScreenBrightness=0.39763778
+  mScreenReady=true
+";
+
+    fn display(state: &str) -> String {
+        DISPLAY.replace('%', state)
+    }
+
+    #[test]
+    fn power() {
+        for (dumpsys, expected) in [
+            (POWER_AWAKE.to_owned(), Wakefulness::Awake),
+            (POWER_ASLEEP.to_owned(), Wakefulness::Asleep),
+            (
+                POWER_AWAKE.replace("=Awake", "=Dreaming"),
+                Wakefulness::Awake,
+            ),
+            (
+                POWER_AWAKE.replace("=Awake", "=Dozing"),
+                Wakefulness::Dozing,
+            ),
+            // The power state wins over the screen state.
+            (
+                format!("{POWER_ASLEEP}{}", display("ON")),
+                Wakefulness::Asleep,
+            ),
+            // What the grep in the query leaves behind.
+            (
+                "  mWakefulness=Awake\n  mScreenState=ON\n".to_owned(),
+                Wakefulness::Awake,
+            ),
+            (
+                "mWakefulness=Asleep\r\nmScreenState=OFF\r\n".to_owned(),
+                Wakefulness::Asleep,
+            ),
+        ] {
+            assert_eq!(Wakefulness::parse(&dumpsys), Some(expected), "{dumpsys}");
+        }
+    }
+
+    #[test]
+    fn screen_state() {
+        for (state, expected) in [
+            ("ON", Wakefulness::Awake),
+            ("VR", Wakefulness::Awake),
+            ("OFF", Wakefulness::Asleep),
+            ("DOZE", Wakefulness::Dozing),
+            ("DOZE_SUSPEND", Wakefulness::Dozing),
+            ("ON_SUSPEND", Wakefulness::Dozing),
+        ] {
+            assert_eq!(
+                Wakefulness::parse(&display(state)),
+                Some(expected),
+                "{state}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_wakefulness_falls_back_to_screen_state() {
+        let dumpsys = format!("  mWakefulness=Hibernating\n{}", display("OFF"));
+        assert_eq!(Wakefulness::parse(&dumpsys), Some(Wakefulness::Asleep));
+    }
+
+    #[test]
+    fn ignores_similar_keys() {
+        let dumpsys =
+            "  mWakefulnessChanging=false\n  mWakefulnessRaw=Awake\n  mScreenStateChanging=ON\n";
+        assert_eq!(Wakefulness::parse(dumpsys), None);
+    }
+
+    #[test]
+    fn unparseable() {
+        for dumpsys in [
+            "",
+            "/system/bin/sh: dumpsys: not found\n",
+            "  mWakefulness=\n",
+            &display("UNKNOWN"),
+        ] {
+            assert_eq!(Wakefulness::parse(dumpsys), None, "{dumpsys}");
+        }
+    }
+}
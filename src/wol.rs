@@ -0,0 +1,346 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    str::FromStr,
+    sync::Once,
+    thread,
+    time::Duration,
+};
+
+use color_eyre::{eyre::Context, Help, Result};
+use mac_address::MacAddress;
+
+use crate::netlink::{self, NUD_FAILED, NUD_INCOMPLETE};
+
+/// How long to wait between repeated packets.
+const REPEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How to send Wake-on-LAN packets to the TV.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The TV's MAC address.
+    pub mac: MacAddress,
+
+    /// The TV's IP address.
+    pub ip: IpAddr,
+
+    /// Where to send the packets. By default, they're sent to the broadcast
+    /// address of the TV's subnet, as well as directly to the TV.
+    pub target: Option<IpAddr>,
+
+    pub port: u16,
+
+    /// The network interface to send the packets from.
+    pub interface: Option<String>,
+
+    /// How many packets to send each time.
+    pub repeat: u32,
+
+    pub password: Option<Password>,
+}
+
+/// A SecureOn password, which some network cards require at the end of the
+/// magic packet. These are six bytes, usually written like MAC addresses.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Password(pub [u8; 6]);
+
+impl FromStr for Password {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<MacAddress>()
+            .map(|m| Self(m.bytes()))
+            .map_err(|_| {
+                format!("invalid SecureOn password {s:?}, expected 6 bytes like a MAC address")
+            })
+    }
+}
+
+impl Debug for Password {
+    /// Doesn't show the actual password, so that it doesn't end up in logs.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Password(..)")
+    }
+}
+
+/// Builds a magic packet, which is six `0xff` bytes followed by the MAC
+/// address 16 times, and the password if there is one.
+pub fn magic_packet(mac: MacAddress, password: Option<Password>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(6 + 16 * 6 + 6);
+    packet.extend_from_slice(&[0xff; 6]);
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac.bytes());
+    }
+    if let Some(Password(password)) = password {
+        packet.extend_from_slice(&password);
+    }
+    packet
+}
+
+/// Sends magic packets to wake up the TV.
+pub fn send(options: &Options) -> Result<()> {
+    let destinations = destinations(options, &tv_subnets(options));
+    log::debug!(
+        "Sending {} Wake-on-LAN packet(s) for {} to {}{}",
+        options.repeat,
+        options.mac,
+        destinations
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(", "),
+        match &options.interface {
+            Some(interface) => format!(" from {interface}"),
+            None => String::new(),
+        }
+    );
+
+    let packet = magic_packet(options.mac, options.password);
+    let sockets = destinations
+        .into_iter()
+        .map(|destination| {
+            let socket =
+                socket(destination.ip(), options).context("Failed to set up Wake-on-LAN socket")?;
+            Ok((socket, destination))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for i in 0..options.repeat {
+        if i > 0 {
+            thread::sleep(REPEAT_INTERVAL);
+        }
+        for (socket, target) in &sockets {
+            socket
+                .send_to(&packet, target)
+                .with_context(|| format!("Failed to send Wake-on-LAN packet to {target}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Where to send the packets, given the subnets of ours that the TV is on.
+///
+/// Unless configured otherwise, the packets are sent to the broadcast address
+/// of the TV's subnet, falling back to 255.255.255.255 if the TV isn't on a
+/// subnet that we're directly connected to.
+///
+/// Some WiFi access points filter out broadcasts to 255.255.255.255, so the
+/// packets are also sent directly to the TV. That only works if the kernel
+/// still knows the TV's MAC address.
+fn destinations(options: &Options, subnets: &[netlink::Address]) -> Vec<SocketAddr> {
+    let targets = match (options.target, options.ip) {
+        (Some(target), _) => vec![target],
+        // IPv6 doesn't have broadcasts.
+        (None, IpAddr::V6(ip)) => vec![ip.into()],
+        (None, IpAddr::V4(ip)) => {
+            let mut targets: Vec<IpAddr> = subnets
+                .iter()
+                .filter_map(|a| a.broadcast)
+                .map(IpAddr::from)
+                .collect();
+            if targets.is_empty() {
+                log::debug!("Found no subnet broadcast address for {ip}");
+                targets.push(Ipv4Addr::BROADCAST.into());
+            }
+            targets.push(ip.into());
+            targets
+        }
+    };
+    targets
+        .into_iter()
+        .map(|target| SocketAddr::new(target, options.port))
+        .collect()
+}
+
+/// Finds the subnets of our network interfaces that the TV is on, if the
+/// packets are going to be broadcast there.
+fn tv_subnets(options: &Options) -> Vec<netlink::Address> {
+    let (None, IpAddr::V4(ip)) = (options.target, options.ip) else {
+        return Vec::new();
+    };
+
+    let subnets: Vec<_> = match netlink::ipv4_addresses() {
+        Ok(addresses) => addresses.into_iter().filter(|a| a.contains(ip)).collect(),
+        Err(e) => {
+            log::warn!("Failed to find the TV's subnet: {e:#}");
+            Vec::new()
+        }
+    };
+
+    // Only a TV on one of our subnets has an entry in the neighbour table.
+    if let Some(subnet) = subnets.last() {
+        let known = matches!(
+            netlink::neighbour_state(ip.into()),
+            Ok(Some(state)) if state & (NUD_INCOMPLETE | NUD_FAILED) == 0
+        );
+        if !known {
+            static HINT: Once = Once::new();
+            HINT.call_once(|| {
+                log::info!(
+                    "The kernel doesn't know the TV's MAC address, so Wake-on-LAN packets sent \
+                     directly to the TV won't reach it. You can add it permanently with \
+                     `ip neigh replace {ip} lladdr {} nud permanent dev {}`",
+                    options.mac,
+                    subnet.interface,
+                )
+            });
+        }
+    }
+
+    subnets
+}
+
+fn socket(target: IpAddr, options: &Options) -> Result<UdpSocket> {
+    let bind_addr: IpAddr = match target {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((bind_addr, 0))?;
+
+    if target.is_ipv4() {
+        socket.set_broadcast(true)?;
+    }
+
+    if let Some(interface) = &options.interface {
+        bind_to_device(&socket, interface)
+            .with_context(|| format!("Failed to bind to interface {interface}"))
+            .suggestion("Binding to an interface requires CAP_NET_RAW on older kernels")?;
+    }
+
+    Ok(socket)
+}
+
+/// Makes `socket` send packets from `interface` regardless of the routing
+/// table.
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    // SAFETY: interface is valid for its whole length, which is passed along.
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr().cast(),
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli;
+
+    const MAC: [u8; 6] = [0xc0, 0xe7, 0xbf, 0x01, 0x02, 0x03];
+
+    #[test]
+    fn packet_layout() {
+        let packet = magic_packet(MacAddress::new(MAC), None);
+        assert_eq!(packet.len(), 102);
+        assert_eq!(packet[..6], [0xff; 6]);
+        for chunk in packet[6..].chunks(6) {
+            assert_eq!(chunk, MAC);
+        }
+    }
+
+    #[test]
+    fn packet_with_password() {
+        let password = "01:23:45:67:89:ab".parse::<Password>().unwrap();
+        let packet = magic_packet(MacAddress::new(MAC), Some(password));
+        assert_eq!(packet.len(), 108);
+        assert_eq!(packet[..102], magic_packet(MacAddress::new(MAC), None));
+        assert_eq!(packet[102..], [0x01, 0x23, 0x45, 0x67, 0x89, 0xab]);
+    }
+
+    #[test]
+    fn passwords() {
+        assert_eq!(
+            "01-23-45-67-89-AB".parse::<Password>(),
+            Ok(Password([0x01, 0x23, 0x45, 0x67, 0x89, 0xab]))
+        );
+        for s in [
+            "",
+            "hunter2",
+            "01:23:45:67:89",
+            "01:23:45:67:89:ab:cd",
+            "01:23:45:67:89:xy",
+        ] {
+            assert_eq!(
+                s.parse::<Password>().unwrap_err(),
+                format!("invalid SecureOn password {s:?}, expected 6 bytes like a MAC address")
+            );
+        }
+        assert_eq!(format!("{:?}", Password([0; 6])), "Password(..)");
+    }
+
+    /// Parses the Wake-on-LAN options like the `on` subcommand does.
+    fn options(args: &[&str]) -> Options {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            wol: cli::Wol,
+        }
+
+        let cli = Cli::parse_from(["tv-power"].iter().chain(args));
+        cli.wol
+            .into_options(MacAddress::new(MAC), "192.0.2.1".parse().unwrap())
+    }
+
+    fn subnet(broadcast: Option<Ipv4Addr>) -> netlink::Address {
+        netlink::Address {
+            interface: "wlan0".to_owned(),
+            local: Ipv4Addr::new(192, 0, 2, 2),
+            prefix_len: 24,
+            broadcast,
+        }
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn default_destination() {
+        assert_eq!(
+            destinations(&options(&[]), &[]),
+            addrs(&["255.255.255.255:9", "192.0.2.1:9"])
+        );
+    }
+
+    #[test]
+    fn subnet_broadcast() {
+        let subnets = [subnet(Some(Ipv4Addr::new(192, 0, 2, 255)))];
+        assert_eq!(
+            destinations(&options(&[]), &subnets),
+            addrs(&["192.0.2.255:9", "192.0.2.1:9"])
+        );
+
+        // Point-to-point links have no broadcast address.
+        assert_eq!(
+            destinations(&options(&[]), &[subnet(None)]),
+            addrs(&["255.255.255.255:9", "192.0.2.1:9"])
+        );
+    }
+
+    #[test]
+    fn configured_destination() {
+        let options = options(&["--wol-address", "192.0.2.127", "--wol-port", "7"]);
+        let subnets = [subnet(Some(Ipv4Addr::new(192, 0, 2, 255)))];
+        assert_eq!(destinations(&options, &subnets), addrs(&["192.0.2.127:7"]));
+    }
+
+    #[test]
+    fn ipv6_destination() {
+        let mut options = options(&[]);
+        options.ip = "2001:db8::1".parse().unwrap();
+        assert_eq!(destinations(&options, &[]), addrs(&["[2001:db8::1]:9"]));
+    }
+}